@@ -0,0 +1,348 @@
+use crate::file_manager::DATABASE;
+
+use rusqlite::{params, Connection};
+use slog::Logger;
+use slog::*;
+
+/// Access rights that can be granted on a path prefix
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Permission {
+    Read,
+    Write,
+    Delete,
+    Lock,
+}
+
+impl Permission {
+    /// Letter used for the permission in the `acl` table, e.g. `rwdl`
+    fn flag(self) -> char {
+        match self {
+            Permission::Read => 'r',
+            Permission::Write => 'w',
+            Permission::Delete => 'd',
+            Permission::Lock => 'l',
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Permission::Read => "read",
+            Permission::Write => "write",
+            Permission::Delete => "delete",
+            Permission::Lock => "lock",
+        }
+    }
+}
+
+/// The rules that apply to a single user
+#[derive(Debug)]
+pub struct Grants {
+    // no rules configured at all, access control is disabled
+    unrestricted: bool,
+    // (path prefix, permission letters)
+    rules: Vec<(String, String)>,
+}
+
+impl Grants {
+    /// Whether any rule matching the file grants the permission
+    pub fn allows(&self, file_name: &str, permission: Permission) -> bool {
+        self.unrestricted
+            || self.rules.iter().any(|(prefix, permissions)| {
+                file_name.starts_with(prefix.as_str()) && permissions.contains(permission.flag())
+            })
+    }
+
+    /// Narrow the grants down to the scope of a bearer token, the token can never add rights
+    pub fn with_scope(self, permissions: &str, paths: &[String]) -> Grants {
+        let rules = if self.unrestricted {
+            paths
+                .iter()
+                .map(|path| (path.clone(), permissions.to_string()))
+                .collect()
+        } else {
+            // intersect every rule with every path of the token
+            let mut scoped = Vec::new();
+            for (prefix, granted) in &self.rules {
+                let letters: String = granted
+                    .chars()
+                    .filter(|c| permissions.contains(*c))
+                    .collect();
+                for path in paths {
+                    if path.starts_with(prefix.as_str()) {
+                        scoped.push((path.clone(), letters.clone()));
+                    } else if prefix.starts_with(path.as_str()) {
+                        scoped.push((prefix.clone(), letters.clone()));
+                    }
+                }
+            }
+            scoped
+        };
+
+        Grants {
+            unrestricted: false,
+            rules,
+        }
+    }
+}
+
+/// Access control lists, stored in the `acl` and `user_group` tables next to the file index.
+///
+/// A rule grants permissions on every file name starting with its prefix, to a user (`alice`),
+/// a group (`@developers`) or everyone including anonymous clients (`*`). Rights are the union
+/// of all matching rules. As long as the `acl` table is empty, access control is disabled.
+pub struct Acl;
+
+impl Acl {
+    /// Create the ACL tables, should be called once at startup
+    /// # Examples
+    /// ```
+    /// Acl::initialize(log.clone());
+    /// ```
+    pub fn initialize(log: Logger) {
+        info!(log, "Initializing access control lists");
+        let conn = Connection::open(DATABASE).unwrap();
+        Acl::create_tables(&conn).unwrap();
+    }
+
+    fn create_tables(conn: &Connection) -> rusqlite::Result<()> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS acl (
+                  id              INTEGER PRIMARY KEY,
+                  principal       TEXT NOT NULL,
+                  prefix          TEXT NOT NULL,
+                  permissions     TEXT NOT NULL,
+                  UNIQUE (principal, prefix)
+                  )",
+            params![],
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS user_group (
+                  username        TEXT NOT NULL,
+                  groupname       TEXT NOT NULL,
+                  PRIMARY KEY (username, groupname)
+                  )",
+            params![],
+        )?;
+        Ok(())
+    }
+
+    /// Load the rules for a user, `None` for an anonymous client
+    /// # Examples
+    /// ```
+    /// let grants = Acl::grants(session.user.as_deref());
+    /// if grants.allows("report.csv", Permission::Read) { ... }
+    /// ```
+    pub fn grants(user: Option<&str>) -> Grants {
+        let conn = Connection::open(DATABASE).unwrap();
+        Acl::load_grants(&conn, user)
+    }
+
+    fn load_grants(conn: &Connection, user: Option<&str>) -> Grants {
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM acl", params![], |row| row.get(0))
+            .unwrap();
+
+        if count == 0 {
+            return Grants {
+                unrestricted: true,
+                rules: Vec::new(),
+            };
+        }
+
+        let mut statement = conn
+            .prepare(
+                "SELECT prefix, permissions FROM acl
+                  WHERE principal = '*'
+                     OR principal = ?1
+                     OR principal IN (SELECT '@' || groupname FROM user_group WHERE username = ?1)",
+            )
+            .unwrap();
+
+        // an anonymous client is NULL and only matches the rules for everyone
+        let rules = statement
+            .query_map(params![user], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .filter_map(|rule| rule.ok())
+            .collect();
+
+        Grants {
+            unrestricted: false,
+            rules,
+        }
+    }
+
+    /// Grant permissions (a combination of `r`, `w`, `d` and `l`) on a prefix, replaces the
+    /// permissions of an existing rule for the same principal and prefix
+    pub fn grant(principal: &str, prefix: &str, permissions: &str) -> rusqlite::Result<()> {
+        let conn = Connection::open(DATABASE)?;
+        conn.execute(
+            "INSERT INTO acl (principal, prefix, permissions) VALUES (?1, ?2, ?3)
+                  ON CONFLICT(principal, prefix) DO UPDATE SET permissions = ?3",
+            params![principal, prefix, permissions],
+        )?;
+        Ok(())
+    }
+
+    /// Remove the rule for a principal and prefix, returns whether there was one
+    pub fn revoke(principal: &str, prefix: &str) -> rusqlite::Result<bool> {
+        let conn = Connection::open(DATABASE)?;
+        let removed = conn.execute(
+            "DELETE FROM acl WHERE principal = ?1 AND prefix = ?2",
+            params![principal, prefix],
+        )?;
+        Ok(removed > 0)
+    }
+
+    /// Add a user to a group, returns false when there is no such user in the user store
+    pub fn join(username: &str, group: &str) -> rusqlite::Result<bool> {
+        let conn = Connection::open(DATABASE)?;
+        Acl::add_member(&conn, username, group)
+    }
+
+    fn add_member(conn: &Connection, username: &str, group: &str) -> rusqlite::Result<bool> {
+        let known: bool = conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM user WHERE username = ?1)",
+            params![username],
+            |row| row.get(0),
+        )?;
+
+        // `*` and groups are principals but never users, they can't be members
+        if !known || username == "*" || username.starts_with('@') {
+            return Ok(false);
+        }
+
+        conn.execute(
+            "INSERT OR IGNORE INTO user_group (username, groupname) VALUES (?1, ?2)",
+            params![username, group],
+        )?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grants(rules: &[(&str, &str)]) -> Grants {
+        Grants {
+            unrestricted: false,
+            rules: rules
+                .iter()
+                .map(|(prefix, permissions)| (prefix.to_string(), permissions.to_string()))
+                .collect(),
+        }
+    }
+
+    fn database() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        Acl::create_tables(&conn).unwrap();
+        conn.execute(
+            "CREATE TABLE user (username TEXT NOT NULL UNIQUE)",
+            params![],
+        )
+        .unwrap();
+        conn.execute("INSERT INTO user (username) VALUES ('alice')", params![])
+            .unwrap();
+        conn
+    }
+
+    fn add_rule(conn: &Connection, principal: &str, prefix: &str, permissions: &str) {
+        conn.execute(
+            "INSERT INTO acl (principal, prefix, permissions) VALUES (?1, ?2, ?3)",
+            params![principal, prefix, permissions],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn rules_match_by_prefix() {
+        let grants = grants(&[("logs-", "r"), ("logs-app", "w"), ("", "l")]);
+
+        assert!(grants.allows("logs-app.txt", Permission::Read));
+        assert!(grants.allows("logs-app.txt", Permission::Write));
+        assert!(grants.allows("logs-db.txt", Permission::Read));
+        assert!(!grants.allows("logs-db.txt", Permission::Write));
+        assert!(!grants.allows("log", Permission::Read));
+        assert!(!grants.allows("report.csv", Permission::Delete));
+        assert!(grants.allows("report.csv", Permission::Lock));
+    }
+
+    #[test]
+    fn no_rules_grant_nothing() {
+        assert!(!grants(&[]).allows("report.csv", Permission::Read));
+    }
+
+    #[test]
+    fn empty_table_is_unrestricted() {
+        let conn = database();
+        let grants = Acl::load_grants(&conn, None);
+        assert!(grants.allows("report.csv", Permission::Delete));
+
+        add_rule(&conn, "alice", "report", "r");
+        let grants = Acl::load_grants(&conn, None);
+        assert!(!grants.allows("report.csv", Permission::Read));
+    }
+
+    #[test]
+    fn rules_apply_to_users_groups_and_everyone() {
+        let conn = database();
+        add_rule(&conn, "*", "pub-", "r");
+        add_rule(&conn, "alice", "alice-", "rw");
+        add_rule(&conn, "@staff", "staff-", "rwd");
+        Acl::add_member(&conn, "alice", "staff").unwrap();
+
+        let alice = Acl::load_grants(&conn, Some("alice"));
+        assert!(alice.allows("pub-a", Permission::Read));
+        assert!(alice.allows("alice-a", Permission::Write));
+        assert!(alice.allows("staff-a", Permission::Delete));
+
+        let anonymous = Acl::load_grants(&conn, None);
+        assert!(anonymous.allows("pub-a", Permission::Read));
+        assert!(!anonymous.allows("alice-a", Permission::Read));
+        assert!(!anonymous.allows("staff-a", Permission::Read));
+    }
+
+    #[test]
+    fn only_known_users_join_groups() {
+        let conn = database();
+        add_rule(&conn, "@staff", "staff-", "rwd");
+
+        assert!(Acl::add_member(&conn, "alice", "staff").unwrap());
+        assert!(!Acl::add_member(&conn, "mallory", "staff").unwrap());
+        assert!(!Acl::add_member(&conn, "*", "staff").unwrap());
+        assert!(!Acl::add_member(&conn, "@admins", "staff").unwrap());
+
+        let anonymous = Acl::load_grants(&conn, None);
+        assert!(!anonymous.allows("staff-a", Permission::Read));
+    }
+
+    #[test]
+    fn token_scope_narrows_unrestricted_grants() {
+        let unrestricted = Grants {
+            unrestricted: true,
+            rules: Vec::new(),
+        };
+        let scoped = unrestricted.with_scope("r", &["pub-".to_string()]);
+
+        assert!(scoped.allows("pub-a", Permission::Read));
+        assert!(!scoped.allows("pub-a", Permission::Write));
+        assert!(!scoped.allows("secret", Permission::Read));
+    }
+
+    #[test]
+    fn token_scope_intersects_with_grants() {
+        let scoped = grants(&[("pub-", "rw"), ("team-a", "rwd")])
+            .with_scope("rd", &["pub-docs".to_string(), "team-".to_string()]);
+
+        // the token path is narrower than the rule
+        assert!(scoped.allows("pub-docs.txt", Permission::Read));
+        assert!(!scoped.allows("pub-docs.txt", Permission::Write));
+        assert!(!scoped.allows("pub-other", Permission::Read));
+        // the rule is narrower than the token path
+        assert!(scoped.allows("team-a1", Permission::Delete));
+        assert!(!scoped.allows("team-b1", Permission::Read));
+        // the token can't add rights the user doesn't have
+        assert!(!scoped.allows("pub-docs.txt", Permission::Delete));
+    }
+}
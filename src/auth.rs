@@ -0,0 +1,427 @@
+use crate::config::Config;
+use crate::file_manager::DATABASE;
+
+use chrono::Utc;
+use lazy_static::lazy_static;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{digest, hmac, pbkdf2};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use slog::Logger;
+use slog::*;
+use std::fs;
+use std::io;
+use std::num::NonZeroU32;
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::Mutex;
+
+const SALT_LENGTH: usize = 16;
+const CREDENTIAL_LENGTH: usize = digest::SHA256_OUTPUT_LEN;
+const PBKDF2_ITERATIONS: u32 = 100_000;
+static PBKDF2_ALGORITHM: pbkdf2::Algorithm = pbkdf2::PBKDF2_HMAC_SHA256;
+
+const TOKEN_KEY_LENGTH: usize = 32;
+
+lazy_static! {
+    // signing key for bearer tokens, loaded from `token_key_file` at startup
+    static ref TOKEN_KEY: Mutex<Option<hmac::Key>> = Mutex::new(None);
+}
+
+/// Failed LOGIN attempts in a row before the connection is closed and the client address is
+/// locked out for `LOGIN_LOCKOUT_SECS`
+pub const MAX_LOGIN_ATTEMPTS: u32 = 3;
+
+/// Users are stored in the `user` table of the file index database, passwords are kept as
+/// salted PBKDF2-HMAC-SHA256 hashes.
+pub struct UserStore;
+
+impl UserStore {
+    /// Create the user table, should be called once at startup
+    /// # Examples
+    /// ```
+    /// UserStore::initialize(log.clone());
+    /// ```
+    pub fn initialize(log: Logger) {
+        info!(log, "Initializing user store");
+        let conn = Connection::open(DATABASE).unwrap();
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS user (
+                  id              INTEGER PRIMARY KEY,
+                  username        TEXT NOT NULL UNIQUE,
+                  salt            BLOB NOT NULL,
+                  password_hash   BLOB NOT NULL
+                  )",
+            params![],
+        )
+        .unwrap();
+    }
+
+    fn derive(salt: &[u8], password: &str) -> [u8; CREDENTIAL_LENGTH] {
+        let mut hash = [0_u8; CREDENTIAL_LENGTH];
+        pbkdf2::derive(
+            PBKDF2_ALGORITHM,
+            NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+            salt,
+            password.as_bytes(),
+            &mut hash,
+        );
+        hash
+    }
+
+    /// Add a user, or replace the password of an existing one
+    /// # Examples
+    /// ```
+    /// UserStore::set_password("alice", "correct horse battery staple")?;
+    /// ```
+    pub fn set_password(username: &str, password: &str) -> rusqlite::Result<()> {
+        let mut salt = [0_u8; SALT_LENGTH];
+        SystemRandom::new().fill(&mut salt).unwrap();
+        let hash = UserStore::derive(&salt, password);
+
+        let conn = Connection::open(DATABASE)?;
+        conn.execute(
+            "INSERT INTO user (username, salt, password_hash) VALUES (?1, ?2, ?3)
+                  ON CONFLICT(username) DO UPDATE SET salt = ?2, password_hash = ?3",
+            params![username, &salt[..], &hash[..]],
+        )?;
+
+        Ok(())
+    }
+
+    /// Whether a user with that name exists
+    pub fn exists(username: &str) -> bool {
+        Connection::open(DATABASE)
+            .and_then(|conn| {
+                conn.query_row(
+                    "SELECT 1 FROM user WHERE username = ?1",
+                    params![username],
+                    |_| Ok(()),
+                )
+                .optional()
+            })
+            .is_ok_and(|found| found.is_some())
+    }
+
+    /// Check a username and password against the store
+    pub fn verify(username: &str, password: &str) -> bool {
+        let conn = match Connection::open(DATABASE) {
+            Ok(conn) => conn,
+            Err(_) => return false,
+        };
+
+        let stored: Option<(Vec<u8>, Vec<u8>)> = conn
+            .query_row(
+                "SELECT salt, password_hash FROM user WHERE username = ?1",
+                params![username],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .unwrap_or(None);
+
+        match stored {
+            Some((salt, hash)) => pbkdf2::verify(
+                PBKDF2_ALGORITHM,
+                NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+                &salt,
+                password.as_bytes(),
+                &hash,
+            )
+            .is_ok(),
+            None => {
+                // take as long as for a known user, the answer mustn't tell whether one exists
+                let _ = pbkdf2::verify(
+                    PBKDF2_ALGORITHM,
+                    NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+                    &[0_u8; SALT_LENGTH],
+                    password.as_bytes(),
+                    &[0_u8; CREDENTIAL_LENGTH],
+                );
+                false
+            }
+        }
+    }
+}
+
+/// What a bearer token allows, signed into the token
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub user: String,
+    /// Permission letters as in the access control lists, e.g. `rw`
+    pub permissions: String,
+    /// Path prefixes the token is valid for
+    pub paths: Vec<String>,
+    /// Unix timestamp after which the token is rejected
+    pub expires: i64,
+}
+
+/// Bearer tokens for clients that can't log in interactively. A token is the base64 encoded
+/// JSON claims and their HMAC-SHA256 signature, joined by a dot.
+pub struct Token;
+
+/// Read a signing key, keys shorter than `TOKEN_KEY_LENGTH` are refused
+fn read_key(key_file: &str) -> io::Result<hmac::Key> {
+    let key = fs::read(key_file)?;
+
+    if key.len() < TOKEN_KEY_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "token key in {} has {} bytes, at least {} are needed",
+                key_file,
+                key.len(),
+                TOKEN_KEY_LENGTH
+            ),
+        ));
+    }
+
+    Ok(hmac::Key::new(hmac::HMAC_SHA256, &key))
+}
+
+impl Token {
+    /// Load the signing key from `token_key_file`, should be called once at startup. Without a
+    /// usable key every token is rejected.
+    /// # Examples
+    /// ```
+    /// Token::initialize(log.clone());
+    /// ```
+    pub fn initialize(log: Logger) {
+        let key_file = match &Config::get().token_key_file {
+            Some(key_file) => key_file.clone(),
+            None => {
+                info!(
+                    log,
+                    "Bearer tokens are disabled, no token_key_file configured"
+                );
+                return;
+            }
+        };
+
+        match read_key(&key_file) {
+            Ok(key) => {
+                info!(log, "Loaded token key from {}", key_file);
+                *TOKEN_KEY.lock().unwrap() = Some(key);
+            }
+            Err(e) => warn!(
+                log,
+                "Bearer tokens are disabled, failed to load token key from {}: {}", key_file, e
+            ),
+        }
+    }
+
+    /// Sign claims with the key in `key_file`, the key is generated if the file doesn't exist
+    /// # Examples
+    /// ```
+    /// let token = Token::mint(&key_file, &claims)?;
+    /// ```
+    pub fn mint(key_file: &str, claims: &Claims) -> io::Result<String> {
+        let key = match read_key(key_file) {
+            Ok(key) => key,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let mut key = [0_u8; TOKEN_KEY_LENGTH];
+                SystemRandom::new().fill(&mut key).unwrap();
+                fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(key_file)
+                    .and_then(|mut file| io::Write::write_all(&mut file, &key))?;
+                hmac::Key::new(hmac::HMAC_SHA256, &key)
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok(Token::sign(&key, claims))
+    }
+
+    fn sign(key: &hmac::Key, claims: &Claims) -> String {
+        let payload =
+            base64::encode_config(serde_json::to_vec(claims).unwrap(), base64::URL_SAFE_NO_PAD);
+        let signature = hmac::sign(key, payload.as_bytes());
+
+        format!(
+            "{}.{}",
+            payload,
+            base64::encode_config(signature.as_ref(), base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    /// Check the signature and expiry of a token and return its claims, the user it was minted
+    /// for must still exist
+    pub fn verify(token: &str) -> std::result::Result<Claims, String> {
+        let key = TOKEN_KEY
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| "tokens are not enabled".to_string())?;
+        let claims = Token::verify_with(&key, token, Utc::now().timestamp())?;
+
+        if !UserStore::exists(&claims.user) {
+            return Err(format!("unknown user: {}", claims.user));
+        }
+
+        Ok(claims)
+    }
+
+    fn verify_with(key: &hmac::Key, token: &str, now: i64) -> std::result::Result<Claims, String> {
+        let mut parts = token.splitn(2, '.');
+        let payload = parts.next().unwrap();
+        let signature = parts
+            .next()
+            .and_then(|signature| base64::decode_config(signature, base64::URL_SAFE_NO_PAD).ok())
+            .ok_or_else(|| "malformed token".to_string())?;
+
+        hmac::verify(key, payload.as_bytes(), &signature)
+            .map_err(|_| "invalid token signature".to_string())?;
+
+        let claims: Claims = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or_else(|| "malformed token".to_string())?;
+
+        if claims.expires <= now {
+            return Err("token expired".to_string());
+        }
+
+        Ok(claims)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_600_000_000;
+
+    fn key(secret: &[u8]) -> hmac::Key {
+        hmac::Key::new(hmac::HMAC_SHA256, secret)
+    }
+
+    fn claims() -> Claims {
+        Claims {
+            user: "alice".to_string(),
+            permissions: "rw".to_string(),
+            paths: vec!["reports-".to_string()],
+            expires: NOW + 60,
+        }
+    }
+
+    #[test]
+    fn accepts_a_signed_token() {
+        let token = Token::sign(&key(b"secret"), &claims());
+        let verified = Token::verify_with(&key(b"secret"), &token, NOW).unwrap();
+
+        assert_eq!(verified.user, "alice");
+        assert_eq!(verified.permissions, "rw");
+        assert_eq!(verified.paths, vec!["reports-".to_string()]);
+    }
+
+    #[test]
+    fn rejects_an_expired_token() {
+        let token = Token::sign(&key(b"secret"), &claims());
+
+        assert!(Token::verify_with(&key(b"secret"), &token, NOW + 59).is_ok());
+        assert_eq!(
+            Token::verify_with(&key(b"secret"), &token, NOW + 60).unwrap_err(),
+            "token expired"
+        );
+    }
+
+    #[test]
+    fn rejects_a_tampered_payload() {
+        let token = Token::sign(&key(b"secret"), &claims());
+        let signature = token.split('.').nth(1).unwrap();
+
+        let mut forged = claims();
+        forged.permissions = "rwdl".to_string();
+        let payload = base64::encode_config(
+            serde_json::to_vec(&forged).unwrap(),
+            base64::URL_SAFE_NO_PAD,
+        );
+
+        assert_eq!(
+            Token::verify_with(&key(b"secret"), &format!("{}.{}", payload, signature), NOW)
+                .unwrap_err(),
+            "invalid token signature"
+        );
+    }
+
+    #[test]
+    fn rejects_a_tampered_signature() {
+        let token = Token::sign(&key(b"secret"), &claims());
+        let (payload, signature) = token.split_at(token.find('.').unwrap() + 1);
+
+        let mut signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).unwrap();
+        signature[0] ^= 1;
+        let token = format!(
+            "{}{}",
+            payload,
+            base64::encode_config(&signature, base64::URL_SAFE_NO_PAD)
+        );
+
+        assert_eq!(
+            Token::verify_with(&key(b"secret"), &token, NOW).unwrap_err(),
+            "invalid token signature"
+        );
+    }
+
+    #[test]
+    fn rejects_a_token_signed_with_another_key() {
+        let token = Token::sign(&key(b"other secret"), &claims());
+
+        assert_eq!(
+            Token::verify_with(&key(b"secret"), &token, NOW).unwrap_err(),
+            "invalid token signature"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_tokens() {
+        for token in &["", "no-signature", "payload.!!!", "."] {
+            assert!(Token::verify_with(&key(b"secret"), token, NOW).is_err());
+        }
+    }
+
+    fn key_file(name: &str, content: Option<&[u8]>) -> String {
+        let path = std::env::temp_dir().join(format!("token-key-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        if let Some(content) = content {
+            fs::write(&path, content).unwrap();
+        }
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn refuses_short_keys() {
+        for (name, content) in &[
+            ("empty", &b""[..]),
+            ("short", &[7_u8; TOKEN_KEY_LENGTH - 1][..]),
+        ] {
+            let path = key_file(name, Some(content));
+
+            assert_eq!(
+                read_key(&path).unwrap_err().kind(),
+                io::ErrorKind::InvalidData
+            );
+            assert!(Token::mint(&path, &claims()).is_err());
+            // the key file is left alone
+            assert_eq!(fs::read(&path).unwrap(), *content);
+
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn mint_creates_a_key_and_signs_with_it() {
+        let path = key_file("created", None);
+
+        let token = Token::mint(&path, &claims()).unwrap();
+        assert_eq!(fs::read(&path).unwrap().len(), TOKEN_KEY_LENGTH);
+
+        let key = read_key(&path).unwrap();
+        assert_eq!(Token::verify_with(&key, &token, NOW).unwrap().user, "alice");
+        assert_eq!(Token::mint(&path, &claims()).unwrap(), token);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,233 @@
+use crate::compression::{Encoding, Stored};
+use crate::config::Config;
+use crate::file_manager::{add_column, DATABASE};
+use crate::hash::Algorithm;
+
+use lazy_static::lazy_static;
+use rusqlite::{params, Connection, OptionalExtension};
+use slog::Logger;
+use slog::*;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::{env, fs};
+
+// deduplicated contents, named by algorithm and hash
+const BLOB_ROOT: &str = "./blobs";
+
+lazy_static! {
+    // held while linking to and removing blobs, a blob isn't removed while it gains a link
+    static ref LINKING: Mutex<()> = Mutex::new(());
+}
+
+/// Content-addressed storage for the `dedup` mode, each distinct content is stored once.
+///
+/// A blob lives in `./blobs/<algorithm>/<hash>`, and every file, version and deleted file
+/// with that content is a hard link to it. The `blob` table records how the blob is stored and
+/// the number of these references, a blob nothing links to anymore is removed.
+pub struct Blobs;
+
+impl Blobs {
+    /// Create the blob table and directories, should be called once at startup
+    /// # Examples
+    /// ```
+    /// Blobs::initialize(log.clone());
+    /// ```
+    pub fn initialize(log: Logger) {
+        info!(log, "Initializing blob storage");
+        let conn = Connection::open(DATABASE).unwrap();
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS blob (
+                  algorithm       TEXT NOT NULL,
+                  hash            TEXT NOT NULL,
+                  size            INTEGER NOT NULL,
+                  refs            INTEGER NOT NULL,
+                  PRIMARY KEY (algorithm, hash)
+                  )",
+            params![],
+        )
+        .unwrap();
+        add_column(&conn, "blob", "encoding", "TEXT");
+
+        for algorithm in &[Algorithm::Md5, Algorithm::Sha256, Algorithm::Sha512] {
+            let path = env::current_dir()
+                .unwrap()
+                .join(BLOB_ROOT)
+                .join(algorithm.name());
+            if let Err(e) = fs::create_dir_all(path) {
+                error!(log, "Error occurred: {:?}", e);
+            }
+        }
+    }
+
+    /// Whether uploads are stored deduplicated
+    pub fn enabled() -> bool {
+        Config::get().dedup
+    }
+
+    fn path(algorithm: Algorithm, hash: &str) -> PathBuf {
+        env::current_dir()
+            .unwrap()
+            .join(BLOB_ROOT)
+            .join(algorithm.name())
+            .join(hash.to_uppercase())
+    }
+
+    /// Link the stored content with this hash to `to`, fails with `NotFound` for unknown content
+    /// # Examples
+    /// ```
+    /// let stored = Blobs::link(Algorithm::Sha256, &hash, &upload_path)?;
+    /// ```
+    pub fn link(algorithm: Algorithm, hash: &str, to: &Path) -> io::Result<Stored> {
+        let _linking = LINKING.lock().unwrap();
+        let stored = Blobs::stored(algorithm, hash)?;
+        fs::hard_link(Blobs::path(algorithm, hash), to)?;
+        Blobs::count(algorithm, hash)?;
+        Ok(stored)
+    }
+
+    /// Store the content of a complete upload as a blob, `path` ends up linked to the blob.
+    /// Content that is already stored replaces the upload, returns how the blob is stored.
+    pub fn adopt(
+        path: &Path,
+        algorithm: Algorithm,
+        hash: &str,
+        stored: Stored,
+    ) -> io::Result<Stored> {
+        let _linking = LINKING.lock().unwrap();
+        let blob = Blobs::path(algorithm, hash);
+
+        match fs::hard_link(path, &blob) {
+            Ok(()) => {
+                let conn = Connection::open(DATABASE).map_err(io::Error::other)?;
+                conn.execute(
+                    "INSERT OR REPLACE INTO blob (algorithm, hash, size, refs, encoding)
+                          VALUES (?1, ?2, ?3, 0, ?4)",
+                    params![
+                        algorithm.name(),
+                        hash.to_uppercase(),
+                        stored.size as i64,
+                        stored.encoding.map(Encoding::name)
+                    ],
+                )
+                .map_err(io::Error::other)?;
+            }
+            // stored by another upload in the meantime
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                fs::remove_file(path)?;
+                fs::hard_link(&blob, path)?;
+            }
+            Err(e) => return Err(e),
+        }
+
+        Blobs::count(algorithm, hash)?;
+        Blobs::stored(algorithm, hash)
+    }
+
+    /// How the blob with this hash is stored, `NotFound` for unknown content
+    fn stored(algorithm: Algorithm, hash: &str) -> io::Result<Stored> {
+        let conn = Connection::open(DATABASE).map_err(io::Error::other)?;
+        let row: Option<(i64, Option<String>)> = conn
+            .query_row(
+                "SELECT size, encoding FROM blob WHERE algorithm = ?1 AND hash = ?2",
+                params![algorithm.name(), hash.to_uppercase()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .map_err(io::Error::other)?;
+
+        match row {
+            Some((size, encoding)) => Ok(Stored {
+                encoding: encoding.as_deref().and_then(Encoding::parse),
+                size: size as u64,
+            }),
+            None => Err(io::Error::from(io::ErrorKind::NotFound)),
+        }
+    }
+
+    /// Update the reference count of a blob after a link to it was removed, a blob nothing
+    /// links to anymore is removed
+    /// # Examples
+    /// ```
+    /// Blobs::release(file.algorithm, &file.hash, log.clone());
+    /// ```
+    pub fn release(algorithm: Algorithm, hash: &str, log: Logger) {
+        let _linking = LINKING.lock().unwrap();
+        let blob = Blobs::path(algorithm, hash);
+
+        match fs::metadata(&blob) {
+            Ok(metadata) if metadata.nlink() <= 1 => {
+                info!(log, "Removing unreferenced {} blob {}", algorithm, hash);
+                if let Err(e) = fs::remove_file(&blob) {
+                    error!(log, "Failed to remove blob {:?}: {}", blob, e);
+                    return;
+                }
+                if let Err(e) = Blobs::forget(algorithm, hash) {
+                    error!(log, "Failed to forget blob {}: {}", hash, e);
+                }
+            }
+            Ok(_) => {
+                if let Err(e) = Blobs::count(algorithm, hash) {
+                    error!(log, "Failed to count references to blob {}: {}", hash, e);
+                }
+            }
+            // not stored as a blob
+            Err(_) => {}
+        }
+    }
+
+    /// Recount the references to every blob, removing the ones left unreferenced while the
+    /// server wasn't running or by changes to the file root
+    pub fn collect(log: Logger) {
+        let conn = Connection::open(DATABASE).unwrap();
+        let mut statement = conn.prepare("SELECT algorithm, hash FROM blob").unwrap();
+        let blobs: Vec<(String, String)> = statement
+            .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .filter_map(|row| row.ok())
+            .collect();
+
+        info!(log, "Counting references to {} blobs", blobs.len());
+
+        for (algorithm, hash) in blobs {
+            match Algorithm::parse(&algorithm) {
+                Some(algorithm) if Blobs::path(algorithm, &hash).exists() => {
+                    Blobs::release(algorithm, &hash, log.clone())
+                }
+                _ => {
+                    let _ = conn.execute(
+                        "DELETE FROM blob WHERE algorithm = ?1 AND hash = ?2",
+                        params![algorithm, hash],
+                    );
+                }
+            }
+        }
+    }
+
+    // the blob itself is one of its links, every other link is a reference
+    fn count(algorithm: Algorithm, hash: &str) -> io::Result<()> {
+        let metadata = fs::metadata(Blobs::path(algorithm, hash))?;
+        let conn = Connection::open(DATABASE).map_err(io::Error::other)?;
+        conn.execute(
+            "UPDATE blob SET refs = ?3 WHERE algorithm = ?1 AND hash = ?2",
+            params![
+                algorithm.name(),
+                hash.to_uppercase(),
+                metadata.nlink() as i64 - 1
+            ],
+        )
+        .map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    fn forget(algorithm: Algorithm, hash: &str) -> rusqlite::Result<()> {
+        let conn = Connection::open(DATABASE)?;
+        conn.execute(
+            "DELETE FROM blob WHERE algorithm = ?1 AND hash = ?2",
+            params![algorithm.name(), hash.to_uppercase()],
+        )?;
+        Ok(())
+    }
+}
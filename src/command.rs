@@ -1,14 +1,245 @@
-use crate::file_manager::FileManager;
+use crate::acl::{Acl, Grants, Permission};
+use crate::auth::{Claims, UserStore, MAX_LOGIN_ATTEMPTS};
+use crate::blobs::Blobs;
+use crate::compression;
+use crate::compression::{Encoding, StoreWriter, Stored};
+use crate::config::Config;
+use crate::file_manager::{FileManager, TFile};
+use crate::hash::{Algorithm, Hasher};
+use crate::limits::{Limits, Throttle};
+use crate::quota::{Exceeded, Kind, Quota, Usage};
+use crate::request;
+use crate::request::Request;
+use crate::response;
+use crate::response::{Format, Status};
+use crate::session::Session;
+use crate::trash::{Trash, Trashed};
+use crate::versions::{Version, Versions};
 
 use slog::Logger;
 use slog::*;
-use std::convert::TryInto;
-use std::fs::File;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
 use std::io::{Read, Write};
-use std::net::{Shutdown, TcpStream};
+use std::path::Path;
 use std::str;
+use std::time::{Duration, Instant};
 
-const BUFFER_SIZE: usize = 1024;
+const BUFFER_SIZE: usize = 4096;
+
+// LIST paging defaults, keeps responses bounded for large directories
+const DEFAULT_LIST_LIMIT: usize = 1000;
+const MAX_LIST_LIMIT: usize = 10_000;
+
+// how often the quotas are checked again while an upload is received
+const QUOTA_RECHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, PartialEq)]
+enum SortKey {
+    Name,
+    Size,
+    Created,
+}
+
+/// Options for the LIST method, parsed from the request line:
+/// `LIST [filter] [sort=name|size|created] [order=asc|desc] [offset=N] [limit=N] [format=text|json] AFTP/1.0`
+///
+/// A filter containing `*` or `?` is matched as a glob, anything else as a prefix.
+#[derive(Debug)]
+struct ListOptions {
+    filter: Option<String>,
+    sort: SortKey,
+    descending: bool,
+    offset: usize,
+    limit: usize,
+}
+
+impl ListOptions {
+    fn parse(args: &[String]) -> std::result::Result<ListOptions, String> {
+        let mut options = ListOptions {
+            filter: None,
+            sort: SortKey::Name,
+            descending: false,
+            offset: 0,
+            limit: DEFAULT_LIST_LIMIT,
+        };
+
+        for arg in args {
+            let mut pair = arg.splitn(2, '=');
+            let key = pair.next().unwrap();
+
+            match (key, pair.next()) {
+                ("sort", Some(value)) => {
+                    options.sort = match value {
+                        "name" => SortKey::Name,
+                        "size" => SortKey::Size,
+                        "created" => SortKey::Created,
+                        _ => return Err(format!("unknown sort key: {}", value)),
+                    }
+                }
+                ("order", Some(value)) => {
+                    options.descending = match value {
+                        "asc" => false,
+                        "desc" => true,
+                        _ => return Err(format!("unknown order: {}", value)),
+                    }
+                }
+                ("offset", Some(value)) => {
+                    options.offset = value
+                        .parse()
+                        .map_err(|_| format!("invalid offset: {}", value))?
+                }
+                ("limit", Some(value)) => {
+                    let limit: usize = value
+                        .parse()
+                        .map_err(|_| format!("invalid limit: {}", value))?;
+                    options.limit = limit.min(MAX_LIST_LIMIT);
+                }
+                ("format", Some(_)) => {}
+                (_, Some(_)) => return Err(format!("unknown option: {}", arg)),
+                ("*", None) | ("/", None) => options.filter = None,
+                (filter, None) => options.filter = Some(filter.to_string()),
+            }
+        }
+
+        Ok(options)
+    }
+
+    fn matches(&self, file_name: &str) -> bool {
+        match &self.filter {
+            None => true,
+            Some(filter) if filter.contains(['*', '?']) => glob_match(filter, file_name),
+            Some(filter) => file_name.starts_with(filter.as_str()),
+        }
+    }
+
+    /// Filter, sort and page the files, returns the page and the number of matching files
+    fn apply(&self, files: Vec<TFile>) -> (Vec<TFile>, usize) {
+        let mut files: Vec<TFile> = files
+            .into_iter()
+            .filter(|f| self.matches(&f.filename))
+            .collect();
+
+        match self.sort {
+            SortKey::Name => files.sort_by(|a, b| a.filename.cmp(&b.filename)),
+            SortKey::Size => files.sort_by_key(|f| f.size),
+            SortKey::Created => files.sort_by_key(|f| f.created),
+        }
+
+        if self.descending {
+            files.reverse();
+        }
+
+        let total = files.len();
+        let page = files
+            .into_iter()
+            .skip(self.offset)
+            .take(self.limit)
+            .collect();
+
+        (page, total)
+    }
+}
+
+/// Glob match supporting `*` (any sequence) and `?` (any single character)
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = backtrack {
+            p = star_p + 1;
+            n = star_n + 1;
+            backtrack = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Describe the usage of a quota in the requested format, unlimited values are `-` in text
+fn describe_usage(usage: &Usage, format: Format) -> String {
+    match format {
+        Format::Text => {
+            let limit = |limit: Option<u64>| limit.map_or("-".to_string(), |n| n.to_string());
+            format!(
+                "{} {} {} {} {} {}\n",
+                usage.kind.name(),
+                usage.subject,
+                usage.bytes,
+                limit(usage.max_bytes),
+                usage.files,
+                limit(usage.max_files)
+            )
+        }
+        Format::Json => serde_json::to_string(usage).unwrap() + "\n",
+    }
+}
+
+/// Describe a file as a single LIST/STAT line in the requested format
+fn describe(file: &TFile, format: Format) -> String {
+    match format {
+        Format::Text => {
+            let lock_state = if file.locked { "locked" } else { "unlocked" };
+            format!(
+                "{} {} {}:{} {} {}\n",
+                request::encode(&file.filename),
+                file.created,
+                file.algorithm,
+                file.hash,
+                file.size,
+                lock_state
+            )
+        }
+        Format::Json => serde_json::to_string(file).unwrap() + "\n",
+    }
+}
+
+/// Describe a deleted file as a single TRASH line in the requested format
+fn describe_trashed(trashed: &Trashed, format: Format) -> String {
+    match format {
+        Format::Text => format!(
+            "{} {} {} {}:{} {}\n",
+            request::encode(&trashed.filename),
+            trashed.deleted,
+            trashed.deleted_by.as_deref().unwrap_or("-"),
+            trashed.algorithm,
+            trashed.hash,
+            trashed.size
+        ),
+        Format::Json => serde_json::to_string(trashed).unwrap() + "\n",
+    }
+}
+
+/// Describe an earlier version of a file as a single VERSIONS line in the requested format
+fn describe_version(version: &Version, format: Format) -> String {
+    match format {
+        Format::Text => format!(
+            "{} {} {}:{} {} {} {}\n",
+            version.version,
+            version.created,
+            version.algorithm,
+            version.hash,
+            version.size,
+            version.uploader.as_deref().unwrap_or("-"),
+            version.archived
+        ),
+        Format::Json => serde_json::to_string(version).unwrap() + "\n",
+    }
+}
 
 // Command
 #[derive(Debug)]
@@ -16,137 +247,1354 @@ pub struct Command {
     method: String,
     value: String,
     hash: String,
+    body_follows: bool,
+    args: Vec<String>,
+    headers: HashMap<String, String>,
+    token: Option<std::result::Result<Claims, String>>,
 }
 
 impl Command {
-    pub fn new(method: &str, value: &str, hash: &str) -> Command {
+    pub fn from_request(request: Request) -> Command {
         Command {
-            method: method.to_string(),
-            value: value.to_string(),
-            hash: hash.to_string(),
+            method: request.method.clone(),
+            value: request.target().to_string(),
+            hash: request.header("hash").unwrap_or("").to_string(),
+            body_follows: request.body_follows(),
+            args: request.args,
+            headers: request.headers,
+            token: request.token,
+        }
+    }
+
+    /// Claims of the bearer token sent with the request, if it was valid
+    fn claims(&self) -> Option<&Claims> {
+        self.token.as_ref().and_then(|token| token.as_ref().ok())
+    }
+
+    /// User the request acts as, a bearer token takes precedence over the session login
+    fn user<'a>(&'a self, session: &'a Session) -> Option<&'a str> {
+        match self.claims() {
+            Some(claims) => Some(claims.user.as_str()),
+            None => session.user.as_deref(),
+        }
+    }
+
+    /// Access rights for the request, limited to the token scope when a token was sent
+    fn grants(&self, session: &Session) -> Grants {
+        let grants = Acl::grants(self.user(session));
+        match self.claims() {
+            Some(claims) => grants.with_scope(&claims.permissions, &claims.paths),
+            None => grants,
+        }
+    }
+
+    /// End the session when a refused request's body is on its way, it can't be told apart
+    /// from the next request. A client waiting for `100 Continue` hasn't sent it.
+    fn abandon_body(&self, session: &mut Session) {
+        if self.body_follows {
+            session.open = false;
+        }
+    }
+
+    /// Output format requested with `format=text|json`, text by default
+    fn format(&self) -> std::result::Result<Format, String> {
+        match self.args.iter().find_map(|arg| arg.strip_prefix("format=")) {
+            None => Ok(Format::Text),
+            Some(value) => Format::parse(value).ok_or(format!("unknown format: {}", value)),
+        }
+    }
+
+    /// Whether the client listed `encoding` in `accept-encoding=`, to receive compressed files
+    /// as they are stored
+    fn accepts(&self, encoding: Encoding) -> bool {
+        self.args
+            .iter()
+            .filter_map(|arg| arg.strip_prefix("accept-encoding="))
+            .flat_map(|list| list.split(','))
+            .any(|name| Encoding::parse(name.trim()) == Some(encoding))
+    }
+
+    /// Version requested with `version=N`, the current content when there is none
+    fn version(&self) -> std::result::Result<Option<i64>, String> {
+        match self
+            .args
+            .iter()
+            .find_map(|arg| arg.strip_prefix("version="))
+        {
+            None => Ok(None),
+            Some(value) => match value.parse::<i64>() {
+                Ok(version) if version > 0 => Ok(Some(version)),
+                _ => Err(format!("invalid version: {}", value)),
+            },
         }
     }
 
     // execute all methods
-    pub fn execute_method(self, mut stream: TcpStream, log: Logger) {
+    pub fn execute_method(self, session: &mut Session, log: Logger) -> io::Result<()> {
         info!(log, "Executing method: {}", self.method);
 
-        if self.method == "LIST" {
-            let instance = FileManager::get().lock().unwrap();
-            let files = instance.as_ref().unwrap().list().to_vec();
+        let format = match self.format() {
+            Ok(format) => format,
+            Err(e) => {
+                info!(log, "Invalid request: {}", e);
+                return response::write_error(
+                    session.stream(),
+                    Format::Text,
+                    Status::BadRequest,
+                    &e,
+                );
+            }
+        };
+
+        if let Some(Err(e)) = &self.token {
+            info!(log, "Rejecting {} with invalid token: {}", self.method, e);
+            self.abandon_body(session);
+            let message = format!("invalid token: {}", e);
+            return response::write_error(session.stream(), format, Status::Unauthorized, &message);
+        }
+
+        let public = self.method == "LOGIN" || self.method == "QUIT";
+
+        if !public && self.user(session).is_none() && Config::get().require_login {
+            info!(log, "Rejecting {} before login", self.method);
+            self.abandon_body(session);
+            return response::write_error(
+                session.stream(),
+                format,
+                Status::Unauthorized,
+                "login required",
+            );
+        }
 
-            let mut content_length = String::from("AFTP/1.0 200 OK\nContent-Length: 100\n");
+        if let Some(permission) = self.required_permission() {
+            if !self.grants(session).allows(&self.value, permission) {
+                info!(
+                    log,
+                    "Denied {} on {} for {:?}",
+                    permission.name(),
+                    self.value,
+                    self.user(session)
+                );
+                self.abandon_body(session);
+                let message = format!("no {} permission on {}", permission.name(), self.value);
+                return response::write_error(
+                    session.stream(),
+                    format,
+                    Status::Forbidden,
+                    &message,
+                );
+            }
+        }
 
-            for _file in files {
-                let t: String = _file.created.to_string();
-                let _str = _file.filename + " " + &t + " " + &_file.hash + "\n";
-                content_length.push_str(&_str);
+        match self.method.as_str() {
+            "LOGIN" => self.login(session, format, log),
+            "LIST" => self.list(session, format, log),
+            "STAT" => self.stat(session, format, log),
+            "GET" => self.get(session, format, log),
+            "PUT" => self.put(session, format, log),
+            "DELETE" => self.delete(session, format, log),
+            "LOCK" => self.lock(session, format, log, true),
+            "UNLOCK" => self.lock(session, format, log, false),
+            "QUOTA" => self.quota(session, format, log),
+            "VERSIONS" => self.versions(session, format, log),
+            "RESTORE" => self.restore(session, format, log),
+            "TRASH" => self.trash(session, format, log),
+            "UNDELETE" => self.undelete(session, format, log),
+            "PURGE" => self.purge(session, format, log),
+            "QUIT" => {
+                info!(log, "Client requested to end the session");
+                session.open = false;
+                response::write(session.stream(), Status::Ok, &[], "")
             }
+            _ => {
+                let message = format!("unknown method: {}", self.method);
+                response::write_error(session.stream(), format, Status::BadRequest, &message)
+            }
+        }
+    }
+
+    /// Permission needed on the target file, LIST and TRASH are filtered per file instead
+    fn required_permission(&self) -> Option<Permission> {
+        match self.method.as_str() {
+            "STAT" | "GET" | "VERSIONS" => Some(Permission::Read),
+            "PUT" | "RESTORE" | "UNDELETE" => Some(Permission::Write),
+            "DELETE" | "PURGE" => Some(Permission::Delete),
+            "LOCK" | "UNLOCK" => Some(Permission::Lock),
+            _ => None,
+        }
+    }
+
+    fn login(&self, session: &mut Session, format: Format, log: Logger) -> io::Result<()> {
+        let password = self.args.get(1).map(String::as_str).unwrap_or("");
+        let ip = session.peer.ip();
 
-            stream.write_all(content_length.as_bytes()).unwrap();
-            stream
-                .shutdown(Shutdown::Both)
-                .expect("failed to shutdown stream");
+        if Limits::login_locked(ip) {
+            warn!(
+                log,
+                "Refusing login for {} from locked out {}", self.value, ip
+            );
+            session.open = false;
+            return response::write_error(
+                session.stream(),
+                format,
+                Status::TooManyRequests,
+                "too many failed logins",
+            );
         }
 
-        if self.method == "PUT" {
-            // only difference is: hash check
-            info!(log, "PUT method: {}", self.method);
-            info!(log, "Value      : {}", self.value);
-            info!(log, "Hash       : {}", self.hash);
+        if UserStore::verify(&self.value, password) {
+            info!(log, "User {} logged in from {}", self.value, session.peer);
+            session.user = Some(self.value.clone());
+            Limits::login_succeeded(ip);
+            return response::write(session.stream(), Status::Ok, &[], "");
+        }
+
+        let attempts = Limits::login_failed(ip);
+        warn!(
+            log,
+            "Failed login for {} from {} (attempt {})", self.value, session.peer, attempts
+        );
+
+        if attempts >= MAX_LOGIN_ATTEMPTS {
+            session.open = false;
+        }
+
+        response::write_error(
+            session.stream(),
+            format,
+            Status::Unauthorized,
+            "invalid username or password",
+        )
+    }
 
-            let mut buf = Vec::with_capacity(BUFFER_SIZE);
-            let stream = stream.try_clone().unwrap();
+    fn not_found(&self, session: &mut Session, format: Format, log: Logger) -> io::Result<()> {
+        // the file may just not have been reached by the startup scan yet
+        if FileManager::is_indexing() {
+            info!(log, "File not indexed yet: {}", self.value);
+            let message = format!("still indexing, file not available yet: {}", self.value);
+            return response::write_error(
+                session.stream(),
+                format,
+                Status::ServiceUnavailable,
+                &message,
+            );
+        }
 
-            FileManager::get()
-                .lock()
-                .unwrap()
-                .as_mut()
-                .unwrap()
-                .get_files(log.clone());
+        info!(log, "Did not find following file: {}", self.value);
+        let message = format!("file not found: {}", self.value);
+        response::write_error(session.stream(), format, Status::NotFound, &message)
+    }
+
+    fn version_not_found(
+        &self,
+        session: &mut Session,
+        format: Format,
+        version: i64,
+        log: Logger,
+    ) -> io::Result<()> {
+        info!(log, "Did not find version {} of {}", version, self.value);
+        let message = format!("version {} of {} not found", version, self.value);
+        response::write_error(session.stream(), format, Status::NotFound, &message)
+    }
+
+    fn locked(&self, session: &mut Session, format: Format, log: Logger) -> io::Result<()> {
+        info!(log, "File is locked by another connection: {}", self.value);
+        let message = format!("file is locked: {}", self.value);
+        response::write_error(session.stream(), format, Status::Locked, &message)
+    }
+
+    fn list(&self, session: &mut Session, format: Format, log: Logger) -> io::Result<()> {
+        let options = match ListOptions::parse(&self.args) {
+            Ok(options) => options,
+            Err(e) => {
+                info!(log, "Invalid LIST request: {}", e);
+                return response::write_error(session.stream(), format, Status::BadRequest, &e);
+            }
+        };
+
+        let instance = FileManager::get().lock().unwrap();
+        let files = instance.as_ref().unwrap().list();
+        drop(instance);
+
+        // only files the client may read are listed
+        let grants = self.grants(session);
+        let files = files
+            .into_iter()
+            .filter(|f| grants.allows(&f.filename, Permission::Read))
+            .collect();
+
+        let (files, total) = options.apply(files);
+        info!(log, "Listing {} of {} matching files", files.len(), total);
+
+        let mut body = String::new();
+
+        for _file in files {
+            body.push_str(&describe(&_file, format));
+        }
+
+        // the listing is incomplete until the startup scan is done
+        let mut headers = vec![("Total-Count", total.to_string())];
+        if FileManager::is_indexing() {
+            headers.push(("Indexing", "true".to_string()));
+        }
+
+        response::write(session.stream(), Status::Ok, &headers, &body)
+    }
+
+    fn stat(&self, session: &mut Session, format: Format, log: Logger) -> io::Result<()> {
+        let instance = FileManager::get().lock().unwrap();
+        let file = instance.as_ref().unwrap().find(&self.value);
+        drop(instance);
+
+        match file {
+            Some(file) => {
+                response::write(session.stream(), Status::Ok, &[], &describe(&file, format))
+            }
+            None => self.not_found(session, format, log),
+        }
+    }
 
-            match stream
-                .take(BUFFER_SIZE.try_into().unwrap())
-                .read_to_end(&mut buf)
-            {
-                Ok(_) => {
-                    info!(log, "{:?}", buf);
+    fn get(&self, session: &mut Session, format: Format, log: Logger) -> io::Result<()> {
+        let (path, encoding, size) = match self.version() {
+            Ok(None) => {
+                let instance = FileManager::get().lock().unwrap();
+                let _file = instance.as_ref().unwrap().find(&self.value);
+                drop(instance);
+
+                match _file {
+                    Some(_file) => (_file.path, _file.encoding, _file.size),
+                    None => return self.not_found(session, format, log),
                 }
+            }
+            Ok(Some(version)) => match Versions::find(&self.value, version) {
+                Some(version) => (version.path, version.encoding, version.size),
+                None => return self.version_not_found(session, format, version, log),
+            },
+            Err(e) => {
+                info!(log, "Invalid GET request: {}", e);
+                return response::write_error(session.stream(), format, Status::BadRequest, &e);
+            }
+        };
+
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) => {
+                error!(log, "Failed to open {}: {}", path, e);
+                return self.not_found(session, format, log);
+            }
+        };
+        let stored_size = file.metadata()?.len();
+
+        info!(log, "Found file, size of file: {}", stored_size);
+        info!(log, "Handling current file: {}", self.value);
+
+        // a compressed file is sent as stored to clients accepting its encoding
+        let mut headers = Vec::new();
+        let (mut content, length): (Box<dyn Read + Send>, u64) = match encoding {
+            None => {
+                headers.push(("File-Size", stored_size.to_string()));
+                (Box::new(file), stored_size)
+            }
+            Some(encoding) if self.accepts(encoding) => {
+                headers.push(("File-Size", size.to_string()));
+                headers.push(("Content-Encoding", encoding.name().to_string()));
+                (Box::new(file), stored_size)
+            }
+            Some(encoding) => {
+                headers.push(("File-Size", size.to_string()));
+                (compression::reader(file, Some(encoding))?, size)
+            }
+        };
+
+        response::write_head(session.stream(), Status::Ok, &headers, length)?;
+
+        let mut remaining_data = length;
+        let mut buf = [0_u8; BUFFER_SIZE];
+        let mut throttle = Throttle::new(Config::get().limits.get_bytes_per_sec);
+
+        while remaining_data != 0 {
+            // read chunk of file
+            let n = content.read(&mut buf)?;
+            if n == 0 {
+                // the file shrunk while sending, the announced length can't be met anymore
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("file truncated while sending: {}", self.value),
+                ));
+            }
+            let n = n.min(remaining_data as usize);
+            throttle.consume(n);
+            session.stream().write_all(&buf[0..n])?;
+            remaining_data -= n as u64;
+        }
+
+        info!(log, "Done sending file: {}", self.value);
+        Ok(())
+    }
+
+    fn put(&self, session: &mut Session, format: Format, log: Logger) -> io::Result<()> {
+        info!(log, "PUT method: {}", self.method);
+        info!(log, "Value      : {}", self.value);
+        info!(log, "Hash       : {}", self.hash);
+
+        let content_length = self
+            .headers
+            .get("content-length")
+            .and_then(|value| value.parse::<u64>().ok());
+
+        // the Hash header is in the configured algorithm unless the client names another one
+        let algorithm = match self.headers.get("hash-algorithm") {
+            Some(name) => Algorithm::parse(name).ok_or_else(|| {
+                "unknown Hash-Algorithm, expected md5, sha256 or sha512".to_string()
+            }),
+            None => Ok(Config::get().hash_algorithm),
+        }
+        .and_then(|algorithm| match algorithm.is_valid_hash(&self.hash) {
+            true => Ok(algorithm),
+            false => Err(format!("Hash is not a hex {} digest", algorithm)),
+        });
+
+        let error = if content_length.is_none() {
+            Some("missing or invalid Content-Length header".to_string())
+        } else if self.hash.is_empty() {
+            Some("missing Hash header".to_string())
+        } else if let Err(message) = &algorithm {
+            Some(message.clone())
+        } else if !FileManager::is_valid_name(&self.value) {
+            Some(format!("invalid file name: {}", self.value))
+        } else {
+            None
+        };
+
+        if let Some(message) = error {
+            info!(log, "Rejecting upload: {}", message);
+            self.abandon_body(session);
+            return response::write_error(session.stream(), format, Status::BadRequest, &message);
+        }
+
+        let content_length = content_length.unwrap();
+        let algorithm = algorithm.unwrap();
+
+        // refuse what can't be stored before reading any of the body
+        let config = Config::get();
+        let refusal = match config.max_upload_bytes {
+            Some(max_upload_bytes) if content_length > max_upload_bytes => Some((
+                Status::PayloadTooLarge,
+                format!(
+                    "upload of {} bytes exceeds the limit of {} bytes",
+                    content_length, max_upload_bytes
+                ),
+            )),
+            _ => match FileManager::free_space() {
+                Ok(free) if free < content_length.saturating_add(config.min_free_bytes) => Some((
+                    Status::InsufficientStorage,
+                    format!(
+                        "not enough space for {} bytes, {} bytes available",
+                        content_length,
+                        free.saturating_sub(config.min_free_bytes)
+                    ),
+                )),
+                Ok(_) => None,
                 Err(e) => {
-                    info!(log, "Failed to receive data: {}", e);
+                    error!(log, "Failed to check free space: {}", e);
+                    Some((
+                        Status::InternalError,
+                        "failed to check free space".to_string(),
+                    ))
                 }
+            },
+        };
+
+        if let Some((status, message)) = refusal {
+            info!(log, "Rejecting upload: {}", message);
+            self.abandon_body(session);
+            return response::write_error(session.stream(), format, status, &message);
+        }
+
+        let user = self.user(session).map(String::from);
+
+        // the space is held until the upload is in the index or discarded
+        let _reservation = match Quota::reserve(user.as_deref(), &self.value, content_length) {
+            Ok(reservation) => reservation,
+            Err(message) => {
+                info!(log, "Rejecting upload: {}", message);
+                self.abandon_body(session);
+                return response::write_error(
+                    session.stream(),
+                    format,
+                    Status::QuotaExceeded,
+                    &message,
+                );
+            }
+        };
+
+        let owned_lock = session.locked_files.contains(&self.value);
+
+        let mut instance = FileManager::get().lock().unwrap();
+        let manager = instance.as_mut().unwrap();
+        let existing = manager.find(&self.value);
+
+        if let Some(existing) = existing {
+            if existing.locked && !owned_lock {
+                drop(instance);
+                self.abandon_body(session);
+                return self.locked(session, format, log);
+            }
+
+            // keep other connections out while the upload is in progress
+            manager.lock_file(&self.value, true);
+            if !owned_lock {
+                session.locked_files.push(self.value.clone());
             }
         }
+        drop(instance);
 
-        if self.method == "DELETE" {
-            let mut n = "404\n";
-            let mut found = false;
-            let instance = FileManager::get().lock().unwrap();
-            let files = instance.as_ref().unwrap().list().to_vec();
-            for _file in &files {
-                if self.value == _file.filename {
-                    info!(
-                        log,
-                        "Found file: {} matches hash: {}", _file.filename, _file.hash
-                    );
-                    found = true;
-                    let file = _file.path.clone();
-                    info!(log, "Found file, removing from file system: {}", file);
-                    let removed = std::fs::remove_file(file).is_ok();
-                    if removed {
-                        let index = files.iter().position(|x| *x == _file.clone()).unwrap();
-                        instance.as_ref().unwrap().list().remove(index);
-                        n = "200 OK\nContent-Length: 100\n";
-                    }
+        // the upload is written next to the file root and only moved in once complete,
+        // an interrupted upload never leaves a truncated file behind
+        let upload_path = FileManager::upload_path(&self.value);
+
+        // content that is already stored isn't written again
+        let grants = self.grants(session);
+        let known = self.take_known(
+            &upload_path,
+            algorithm,
+            content_length,
+            &grants,
+            log.clone(),
+        );
+
+        if !self.body_follows {
+            if let Some(stored) = known {
+                info!(log, "Content of {} is already present", self.value);
+                self.commit_upload(
+                    session,
+                    &upload_path,
+                    self.hash.to_uppercase(),
+                    algorithm,
+                    stored,
+                    user,
+                    owned_lock,
+                    log,
+                )?;
+                return response::write(
+                    session.stream(),
+                    Status::Ok,
+                    &[("Already-Present", "true".to_string())],
+                    "",
+                );
+            }
+            response::write(session.stream(), Status::Continue, &[], "")?;
+        }
+
+        let sink = if known.is_some() {
+            info!(log, "Content of {} is already stored", self.value);
+            None
+        } else {
+            Some(upload_path.as_path())
+        };
+        let encoding = Config::get().compression;
+
+        let received = self.receive(
+            session,
+            sink,
+            content_length,
+            algorithm,
+            encoding,
+            user.as_deref(),
+        );
+        let hash = match received {
+            Ok(hash) => hash,
+            Err(e) if e.get_ref().is_some_and(|e| e.is::<Exceeded>()) => {
+                info!(log, "Stopping upload of {}: {}", self.value, e);
+                self.discard_upload(&upload_path, algorithm, log);
+                if !owned_lock {
+                    self.release_upload_lock(session);
                 }
+                // the rest of the body is never read
+                session.open = false;
+                return response::write_error(
+                    session.stream(),
+                    format,
+                    Status::QuotaExceeded,
+                    &e.to_string(),
+                );
+            }
+            Err(e) => {
+                info!(log, "Upload of {} failed, discarding it: {}", self.value, e);
+                self.discard_upload(&upload_path, algorithm, log);
+                return Err(e);
+            }
+        };
+
+        if !hash.eq_ignore_ascii_case(&self.hash) {
+            info!(
+                log,
+                "Discarding upload of {}: hash {} doesn't match {}", self.value, hash, self.hash
+            );
+            self.discard_upload(&upload_path, algorithm, log.clone());
+            if !owned_lock {
+                self.release_upload_lock(session);
+            }
+            let message = format!("{} hash of the content is {}", algorithm, hash);
+            return response::write_error(session.stream(), format, Status::BadRequest, &message);
+        }
+
+        if let Err(message) = Quota::recheck(user.as_deref(), &self.value, content_length) {
+            info!(log, "Discarding upload of {}: {}", self.value, message);
+            self.discard_upload(&upload_path, algorithm, log.clone());
+            if !owned_lock {
+                self.release_upload_lock(session);
             }
+            return response::write_error(
+                session.stream(),
+                format,
+                Status::QuotaExceeded,
+                &message,
+            );
+        }
+
+        let mut stored = known.unwrap_or(Stored {
+            encoding,
+            size: content_length,
+        });
 
-            let s = format!("AFTP/1.0 {}\n", n);
-            stream.write_all(s.as_bytes()).unwrap();
-            if !found {
-                info!(log, "Did not find following file: {}", self.value);
+        if Blobs::enabled() && known.is_none() {
+            // kept as a file of its own if it can't be shared
+            match Blobs::adopt(&upload_path, algorithm, &hash, stored) {
+                Ok(blob) => stored = blob,
+                Err(e) => error!(log, "Failed to store the content of {}: {}", self.value, e),
             }
-            stream.shutdown(Shutdown::Both).unwrap();
         }
 
-        if self.method == "GET" {
-            let instance = FileManager::get().lock().unwrap();
-            let files = instance.as_ref().unwrap().list().to_vec();
+        self.commit_upload(
+            session,
+            &upload_path,
+            hash,
+            algorithm,
+            stored,
+            user,
+            owned_lock,
+            log,
+        )?;
+        response::write(session.stream(), Status::Ok, &[], "")
+    }
+
+    /// Put content the server already holds with the announced hash and size at `path`. A stored
+    /// blob is linked, which also keeps it from being removed while the body is read. Otherwise
+    /// an indexed file is copied, if the client waits to learn whether to send the body.
+    ///
+    /// Only content of a file the client may read is taken, knowing a hash mustn't be enough to
+    /// get a copy of a file, or to find out it exists.
+    fn take_known(
+        &self,
+        path: &Path,
+        algorithm: Algorithm,
+        size: u64,
+        grants: &Grants,
+        log: Logger,
+    ) -> Option<Stored> {
+        let instance = FileManager::get().lock().unwrap();
+        let readable = instance
+            .as_ref()
+            .unwrap()
+            .find_content(algorithm, &self.hash, |name| {
+                grants.allows(name, Permission::Read)
+            })?;
+        drop(instance);
 
-            for _file in files {
-                if _file.filename == self.value {
-                    let mut file = File::open(_file.path).unwrap();
-                    let file_size = file.metadata().unwrap().len();
+        let linked = match Blobs::enabled() {
+            true => Blobs::link(algorithm, &self.hash, path).ok(),
+            false => None,
+        };
 
-                    info!(log, "Found file, size of file: {}", file_size);
-                    info!(log, "Handling current file: {}", _file.filename);
+        let known = linked.or_else(|| {
+            if self.body_follows {
+                return None;
+            }
+            fs::copy(&readable.path, path).ok()?;
+            Some(Stored {
+                encoding: readable.encoding,
+                size: readable.size,
+            })
+        });
 
-                    // open file in binary mode
-                    let mut remaining_data = file_size as i32;
-                    // buffer 4k, maybe enough for large files
-                    let mut buf = [0 as u8; 4096];
-                    let t: String = file_size.to_string();
+        // the same hash with another size is a broken client, the body will tell
+        if known.is_some_and(|stored| stored.size != size) {
+            self.discard_upload(path, algorithm, log);
+            return None;
+        }
+        known
+    }
 
-                    let s = format!("AFTP/1.0 200 OK\nContent-Length: {}\nFile-Size: {}\n", t, t);
+    /// Remove an upload that won't be committed, along with the content it may have linked to
+    fn discard_upload(&self, path: &Path, algorithm: Algorithm, log: Logger) {
+        let _ = fs::remove_file(path);
+        Blobs::release(algorithm, &self.hash, log);
+    }
 
-                    info!(log, "Sending GET response:\n {}", s);
+    /// Move a complete upload into the file root in place of the current file, which is kept
+    /// as a version, and index it
+    #[allow(clippy::too_many_arguments)]
+    fn commit_upload(
+        &self,
+        session: &mut Session,
+        upload_path: &Path,
+        hash: String,
+        algorithm: Algorithm,
+        stored: Stored,
+        user: Option<String>,
+        owned_lock: bool,
+        log: Logger,
+    ) -> io::Result<()> {
+        let file_path = FileManager::file_path(&self.value);
 
-                    stream.write_all(s.as_bytes()).unwrap();
+        let instance = FileManager::get().lock().unwrap();
+        let previous = instance.as_ref().unwrap().find(&self.value);
+        drop(instance);
 
-                    while remaining_data != 0 {
-                        // read chunk of file
-                        let file_chunk = file.read(&mut buf);
-                        if let Ok(n) = file_chunk {
-                            stream.write_all(&buf[0..n]).unwrap();
-                            info!(log, "Sending file chunk with byte size: {}", n);
-                            remaining_data -= n as i32;
-                            info!(log, "Remaining data for file: {}", remaining_data);
-                        }
-                    }
-                    info!(log, "Done sending file: {}", _file.filename);
-                    stream.shutdown(Shutdown::Both).unwrap();
+        if let Some(previous) = &previous {
+            if let Err(e) = Versions::archive(previous, log.clone()) {
+                error!(
+                    log,
+                    "Failed to keep the previous version of {}: {}", self.value, e
+                );
+            }
+        }
+
+        // moved in and indexed at once, the watcher must not take it for a foreign file
+        let mut instance = FileManager::get().lock().unwrap();
+        let manager = instance.as_mut().unwrap();
+        fs::rename(upload_path, &file_path)?;
+
+        info!(log, "File writing is done for: {:?}", self.value);
+
+        manager.create(
+            File::open(&file_path)?,
+            self.value.clone(),
+            file_path.to_string_lossy().to_string(),
+            hash,
+            algorithm,
+        );
+        manager.set_stored(&self.value, stored);
+
+        // the new index entry starts out unlocked, keep it locked if the client held the lock
+        if owned_lock {
+            manager.lock_file(&self.value, true);
+        } else {
+            session.locked_files.retain(|name| *name != self.value);
+        }
+        let file = manager.find(&self.value).unwrap();
+        drop(instance);
+
+        // how the file is stored can't be told from the file itself
+        FileManager::store(&file);
+
+        if let Some(previous) = previous {
+            Blobs::release(previous.algorithm, &previous.hash, log.clone());
+        }
+
+        if let Err(e) = Quota::set_owner(&self.value, user.as_deref()) {
+            error!(log, "Failed to record the owner of {}: {}", self.value, e);
+        }
+
+        Ok(())
+    }
+
+    /// Give up the lock taken for an upload that didn't go through
+    fn release_upload_lock(&self, session: &mut Session) {
+        if session.locked_files.contains(&self.value) {
+            let mut instance = FileManager::get().lock().unwrap();
+            instance.as_mut().unwrap().lock_file(&self.value, false);
+            drop(instance);
+            session.locked_files.retain(|name| *name != self.value);
+        }
+    }
+
+    /// Stream `content_length` bytes of upload body into the file at `path`, stored with
+    /// `encoding`, hashing it on the way so the file doesn't have to be read again. Without a
+    /// path the body is only hashed. The quotas of `user` are checked again now and then, the
+    /// upload stops with an `Exceeded` error once it no longer fits.
+    fn receive(
+        &self,
+        session: &mut Session,
+        path: Option<&Path>,
+        content_length: u64,
+        algorithm: Algorithm,
+        encoding: Option<Encoding>,
+        user: Option<&str>,
+    ) -> io::Result<String> {
+        let mut file = match path {
+            Some(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .truncate(true)
+                    .open(path)?;
+                Some(StoreWriter::new(file, encoding)?)
+            }
+            None => None,
+        };
+
+        let mut remaining_data = content_length;
+        let mut buf = [0_u8; BUFFER_SIZE];
+        let mut throttle = Throttle::new(Config::get().limits.put_bytes_per_sec);
+        let mut hasher = Hasher::new(algorithm);
+        let mut checked = Instant::now();
+
+        while remaining_data != 0 {
+            if checked.elapsed() >= QUOTA_RECHECK_INTERVAL {
+                Quota::recheck(user, &self.value, content_length)
+                    .map_err(|message| io::Error::other(Exceeded(message)))?;
+                checked = Instant::now();
+            }
+
+            let chunk = remaining_data.min(BUFFER_SIZE as u64) as usize;
+            let size = session.reader().read(&mut buf[0..chunk])?;
+            if size == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            if let Some(file) = &mut file {
+                file.write_all(&buf[0..size])?;
+            }
+            hasher.update(&buf[0..size]);
+            remaining_data -= size as u64;
+            throttle.consume(size);
+        }
+
+        if let Some(file) = file {
+            file.finish()?.sync_all()?;
+        }
+        Ok(hasher.finish())
+    }
+
+    fn delete(&self, session: &mut Session, format: Format, log: Logger) -> io::Result<()> {
+        let mut instance = FileManager::get().lock().unwrap();
+        let manager = instance.as_mut().unwrap();
+
+        let _file = match manager.find(&self.value) {
+            Some(_file) => _file,
+            None => {
+                drop(instance);
+                return self.not_found(session, format, log);
+            }
+        };
+
+        if _file.locked && !session.locked_files.contains(&_file.filename) {
+            drop(instance);
+            return self.locked(session, format, log);
+        }
+
+        info!(
+            log,
+            "Found file: {} matches hash: {}", _file.filename, _file.hash
+        );
+        let owner = Quota::owner(&_file.filename);
+        let removed = if Trash::enabled() {
+            Trash::discard(&_file, self.user(session), owner, log.clone())
+        } else {
+            info!(log, "Found file, removing from file system: {}", _file.path);
+            std::fs::remove_file(&_file.path)
+        };
+
+        if let Err(e) = removed {
+            drop(instance);
+            error!(log, "Failed to remove {}: {}", _file.path, e);
+            let message = format!("failed to remove file: {}", self.value);
+            return response::write_error(
+                session.stream(),
+                format,
+                Status::InternalError,
+                &message,
+            );
+        }
+
+        manager.remove(&_file.filename);
+        drop(instance);
+        session.locked_files.retain(|name| *name != _file.filename);
+        Blobs::release(_file.algorithm, &_file.hash, log.clone());
+
+        if let Err(e) = Quota::set_owner(&_file.filename, None) {
+            error!(
+                log,
+                "Failed to forget the owner of {}: {}", _file.filename, e
+            );
+        }
+
+        response::write(session.stream(), Status::Ok, &[], "")
+    }
+
+    /// `QUOTA [user|prefix <name>]`, usage and limits of the client's own user by default
+    fn quota(&self, session: &mut Session, format: Format, log: Logger) -> io::Result<()> {
+        let args: Vec<&str> = self
+            .args
+            .iter()
+            .map(String::as_str)
+            .filter(|arg| !arg.starts_with("format="))
+            .collect();
+        let user = self.user(session).map(String::from);
+
+        let (kind, subject) = match (args.as_slice(), &user) {
+            ([], Some(user)) => (Kind::User, user.clone()),
+            ([kind, subject], _) if Kind::parse(kind).is_some() => {
+                (Kind::parse(kind).unwrap(), subject.to_string())
+            }
+            _ => {
+                let message = "usage: QUOTA [user|prefix <name>]";
+                info!(log, "Invalid QUOTA request: {:?}", args);
+                return response::write_error(
+                    session.stream(),
+                    format,
+                    Status::BadRequest,
+                    message,
+                );
+            }
+        };
+
+        // other users' usage is private, a prefix needs read permission
+        let allowed = match kind {
+            Kind::User => user.as_deref() == Some(subject.as_str()),
+            Kind::Prefix => self.grants(session).allows(&subject, Permission::Read),
+        };
+        if !allowed {
+            info!(
+                log,
+                "Denied quota of {} {} for {:?}",
+                kind.name(),
+                subject,
+                user
+            );
+            let message = format!("no access to the quota of {} {}", kind.name(), subject);
+            return response::write_error(session.stream(), format, Status::Forbidden, &message);
+        }
+
+        let usage = Quota::usage(kind, &subject);
+        response::write(
+            session.stream(),
+            Status::Ok,
+            &[],
+            &describe_usage(&usage, format),
+        )
+    }
+
+    /// `VERSIONS <name>`, the earlier versions of a file, newest first
+    fn versions(&self, session: &mut Session, format: Format, log: Logger) -> io::Result<()> {
+        let versions = match Versions::list(&self.value) {
+            Ok(versions) => versions,
+            Err(e) => {
+                error!(log, "Failed to list versions of {}: {}", self.value, e);
+                return response::write_error(
+                    session.stream(),
+                    format,
+                    Status::InternalError,
+                    "failed to list versions",
+                );
+            }
+        };
+
+        info!(log, "Listing {} versions of {}", versions.len(), self.value);
+
+        let body: String = versions
+            .iter()
+            .map(|version| describe_version(version, format))
+            .collect();
+        response::write(session.stream(), Status::Ok, &[], &body)
+    }
+
+    /// `RESTORE <name> <version>`, make an earlier version the current content again. The
+    /// content it replaces is kept as a version like on PUT.
+    fn restore(&self, session: &mut Session, format: Format, log: Logger) -> io::Result<()> {
+        let version = match self.args.get(1).and_then(|arg| arg.parse::<i64>().ok()) {
+            Some(version) => version,
+            None => {
+                info!(log, "Invalid RESTORE request: {:?}", self.args);
+                return response::write_error(
+                    session.stream(),
+                    format,
+                    Status::BadRequest,
+                    "usage: RESTORE <name> <version>",
+                );
+            }
+        };
+
+        let version = match Versions::find(&self.value, version) {
+            Some(found) => found,
+            None => return self.version_not_found(session, format, version, log),
+        };
+
+        let user = self.user(session).map(String::from);
+
+        let _reservation = match Quota::reserve(user.as_deref(), &self.value, version.size) {
+            Ok(reservation) => reservation,
+            Err(message) => {
+                info!(log, "Rejecting restore: {}", message);
+                return response::write_error(
+                    session.stream(),
+                    format,
+                    Status::QuotaExceeded,
+                    &message,
+                );
+            }
+        };
+
+        let owned_lock = session.locked_files.contains(&self.value);
+
+        let mut instance = FileManager::get().lock().unwrap();
+        let manager = instance.as_mut().unwrap();
+
+        if let Some(existing) = manager.find(&self.value) {
+            if existing.locked && !owned_lock {
+                drop(instance);
+                return self.locked(session, format, log);
+            }
+
+            manager.lock_file(&self.value, true);
+            if !owned_lock {
+                session.locked_files.push(self.value.clone());
+            }
+        }
+        drop(instance);
+
+        // with dedup on the file shares the version's blob like any other deduplicated file, and
+        // mustn't be changed in place. Otherwise it's a copy, so the version stays intact.
+        let upload_path = FileManager::upload_path(&self.value);
+        let copied: io::Result<Stored> = match Blobs::enabled() {
+            true => Blobs::link(version.algorithm, &version.hash, &upload_path),
+            false => Err(io::Error::from(io::ErrorKind::NotFound)),
+        }
+        .or_else(|_| {
+            fs::copy(&version.path, &upload_path)?;
+            Ok(Stored {
+                encoding: version.encoding,
+                size: version.size,
+            })
+        });
+        let stored = match copied {
+            Ok(stored) => stored,
+            Err(e) => {
+                error!(
+                    log,
+                    "Failed to restore version {} of {}: {}", version.version, self.value, e
+                );
+                let _ = fs::remove_file(&upload_path);
+                if !owned_lock {
+                    self.release_upload_lock(session);
                 }
+                return response::write_error(
+                    session.stream(),
+                    format,
+                    Status::InternalError,
+                    "failed to restore version",
+                );
+            }
+        };
+
+        info!(
+            log,
+            "Restoring version {} of {}", version.version, self.value
+        );
+        self.commit_upload(
+            session,
+            &upload_path,
+            version.hash,
+            version.algorithm,
+            stored,
+            user,
+            owned_lock,
+            log,
+        )?;
+        response::write(session.stream(), Status::Ok, &[], "")
+    }
+
+    /// `TRASH [prefix]`, deleted files the client may read, most recently deleted first
+    fn trash(&self, session: &mut Session, format: Format, log: Logger) -> io::Result<()> {
+        let prefix = match self.args.iter().find(|arg| !arg.contains('=')) {
+            Some(prefix) => prefix.as_str(),
+            None => "",
+        };
+
+        let trashed = match Trash::list(prefix) {
+            Ok(trashed) => trashed,
+            Err(e) => {
+                error!(log, "Failed to list the trash: {}", e);
+                return response::write_error(
+                    session.stream(),
+                    format,
+                    Status::InternalError,
+                    "failed to list the trash",
+                );
             }
+        };
+
+        let grants = self.grants(session);
+        let body: String = trashed
+            .iter()
+            .filter(|trashed| grants.allows(&trashed.filename, Permission::Read))
+            .map(|trashed| describe_trashed(trashed, format))
+            .collect();
+        response::write(session.stream(), Status::Ok, &[], &body)
+    }
+
+    /// `UNDELETE <name>`, move the most recently deleted file with this name back from the trash
+    fn undelete(&self, session: &mut Session, format: Format, log: Logger) -> io::Result<()> {
+        let trashed = match Trash::latest(&self.value) {
+            Some(trashed) => trashed,
+            None => {
+                info!(log, "No deleted file named {}", self.value);
+                let message = format!("no deleted file named {}", self.value);
+                return response::write_error(session.stream(), format, Status::NotFound, &message);
+            }
+        };
+
+        // the file counts against its owner again
+        let _reservation = match Quota::reserve(trashed.owner.as_deref(), &self.value, trashed.size)
+        {
+            Ok(reservation) => reservation,
+            Err(message) => {
+                info!(log, "Rejecting undelete: {}", message);
+                return response::write_error(
+                    session.stream(),
+                    format,
+                    Status::QuotaExceeded,
+                    &message,
+                );
+            }
+        };
+
+        let mut instance = FileManager::get().lock().unwrap();
+        let manager = instance.as_mut().unwrap();
+
+        if manager.find(&self.value).is_some() {
+            drop(instance);
+            info!(log, "Not undeleting {}, the name is taken", self.value);
+            let message = format!("file already exists: {}", self.value);
+            return response::write_error(session.stream(), format, Status::Conflict, &message);
         }
+
+        let file_path = FileManager::file_path(&self.value);
+        let undeleted = Trash::take(&trashed, &file_path).and_then(|_| File::open(&file_path));
+        let file = match undeleted {
+            Ok(file) => file,
+            Err(e) => {
+                drop(instance);
+                error!(log, "Failed to undelete {}: {}", self.value, e);
+                let message = format!("failed to undelete file: {}", self.value);
+                return response::write_error(
+                    session.stream(),
+                    format,
+                    Status::InternalError,
+                    &message,
+                );
+            }
+        };
+
+        manager.create(
+            file,
+            self.value.clone(),
+            file_path.to_string_lossy().to_string(),
+            trashed.hash,
+            trashed.algorithm,
+        );
+        manager.set_stored(
+            &self.value,
+            Stored {
+                encoding: trashed.encoding,
+                size: trashed.size,
+            },
+        );
+        let file = manager.find(&self.value).unwrap();
+        drop(instance);
+
+        FileManager::store(&file);
+
+        if let Err(e) = Quota::set_owner(&self.value, trashed.owner.as_deref()) {
+            error!(log, "Failed to record the owner of {}: {}", self.value, e);
+        }
+
+        info!(log, "Undeleted {}", self.value);
+        response::write(session.stream(), Status::Ok, &[], "")
+    }
+
+    /// `PURGE <name>`, remove every deleted file with this name from the trash for good
+    fn purge(&self, session: &mut Session, format: Format, log: Logger) -> io::Result<()> {
+        match Trash::purge(&self.value, log.clone()) {
+            Ok(0) => {
+                info!(log, "No deleted file named {}", self.value);
+                let message = format!("no deleted file named {}", self.value);
+                response::write_error(session.stream(), format, Status::NotFound, &message)
+            }
+            Ok(_) => response::write(session.stream(), Status::Ok, &[], ""),
+            Err(e) => {
+                error!(log, "Failed to purge {}: {}", self.value, e);
+                let message = format!("failed to purge file: {}", self.value);
+                response::write_error(session.stream(), format, Status::InternalError, &message)
+            }
+        }
+    }
+
+    fn lock(
+        &self,
+        session: &mut Session,
+        format: Format,
+        log: Logger,
+        lock: bool,
+    ) -> io::Result<()> {
+        let mut instance = FileManager::get().lock().unwrap();
+        let manager = instance.as_mut().unwrap();
+
+        let _file = match manager.find(&self.value) {
+            Some(_file) => _file,
+            None => {
+                drop(instance);
+                return self.not_found(session, format, log);
+            }
+        };
+
+        let owned_lock = session.locked_files.contains(&_file.filename);
+
+        if _file.locked && !owned_lock {
+            drop(instance);
+            return self.locked(session, format, log);
+        }
+
+        manager.lock_file(&_file.filename, lock);
+        drop(instance);
+
+        if lock && !owned_lock {
+            session.locked_files.push(_file.filename.clone());
+        } else if !lock {
+            session.locked_files.retain(|name| *name != _file.filename);
+        }
+
+        info!(
+            log,
+            "File {}: {}",
+            if lock { "locked" } else { "unlocked" },
+            _file.filename
+        );
+        response::write(session.stream(), Status::Ok, &[], "")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    fn file(filename: &str, size: u64, created: i64) -> TFile {
+        TFile {
+            filename: filename.to_string(),
+            path: String::new(),
+            hash: String::new(),
+            algorithm: Algorithm::Md5,
+            created,
+            size,
+            modified: 0,
+            locked: false,
+            encoding: None,
+        }
+    }
+
+    fn names(files: &[TFile]) -> Vec<&str> {
+        files.iter().map(|f| f.filename.as_str()).collect()
+    }
+
+    #[test]
+    fn glob_star_matches_any_sequence() {
+        assert!(glob_match("*.log", "a.log"));
+        assert!(glob_match("*.log", ".log"));
+        assert!(glob_match("a*", "a"));
+        assert!(glob_match("a*", "abc"));
+        assert!(glob_match("a**", "abc"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*c*e", "abcdcxe"));
+        assert!(!glob_match("a*", "ba"));
+        assert!(!glob_match("*.log", "a.log.gz"));
+    }
+
+    #[test]
+    fn glob_question_mark_matches_a_single_character() {
+        assert!(glob_match("?.txt", "a.txt"));
+        assert!(glob_match("?.txt", "é.txt"));
+        assert!(glob_match("caf?", "café"));
+        assert!(glob_match("??", "日本"));
+        assert!(!glob_match("?", "日本"));
+        assert!(!glob_match("?.txt", ".txt"));
+    }
+
+    #[test]
+    fn list_options_default() {
+        let options = ListOptions::parse(&[]).unwrap();
+        assert_eq!(options.filter, None);
+        assert_eq!(options.sort, SortKey::Name);
+        assert!(!options.descending);
+        assert_eq!(options.offset, 0);
+        assert_eq!(options.limit, DEFAULT_LIST_LIMIT);
+    }
+
+    #[test]
+    fn list_options_parse() {
+        let options = ListOptions::parse(&args(
+            "a* sort=size order=desc offset=5 limit=7 format=json",
+        ))
+        .unwrap();
+        assert_eq!(options.filter.as_deref(), Some("a*"));
+        assert_eq!(options.sort, SortKey::Size);
+        assert!(options.descending);
+        assert_eq!(options.offset, 5);
+        assert_eq!(options.limit, 7);
+
+        assert_eq!(ListOptions::parse(&args("*")).unwrap().filter, None);
+        assert!(ListOptions::parse(&args("sort=owner")).is_err());
+        assert!(ListOptions::parse(&args("order=up")).is_err());
+        assert!(ListOptions::parse(&args("offset=-1")).is_err());
+        assert!(ListOptions::parse(&args("limit=many")).is_err());
+        assert!(ListOptions::parse(&args("color=red")).is_err());
+    }
+
+    #[test]
+    fn list_limit_is_clamped() {
+        let options = ListOptions::parse(&args("limit=99999999")).unwrap();
+        assert_eq!(options.limit, MAX_LIST_LIMIT);
+    }
+
+    #[test]
+    fn list_apply_filters_sorts_and_pages() {
+        let files = || {
+            vec![
+                file("b.log", 30, 1),
+                file("a.log", 10, 3),
+                file("c.txt", 20, 2),
+                file("d.log", 5, 4),
+            ]
+        };
+
+        let (page, total) = ListOptions::parse(&args("*.log")).unwrap().apply(files());
+        assert_eq!(names(&page), ["a.log", "b.log", "d.log"]);
+        assert_eq!(total, 3);
+
+        let (page, _) = ListOptions::parse(&args("sort=size order=desc"))
+            .unwrap()
+            .apply(files());
+        assert_eq!(names(&page), ["b.log", "c.txt", "a.log", "d.log"]);
+
+        let (page, total) = ListOptions::parse(&args("sort=created offset=1 limit=2"))
+            .unwrap()
+            .apply(files());
+        assert_eq!(names(&page), ["c.txt", "a.log"]);
+        assert_eq!(total, 4);
+
+        let (page, _) = ListOptions::parse(&args("c")).unwrap().apply(files());
+        assert_eq!(names(&page), ["c.txt"]);
+    }
+
+    #[test]
+    fn list_offset_past_the_total_is_an_empty_page() {
+        let files = vec![file("a", 1, 1), file("b", 1, 2)];
+        let (page, total) = ListOptions::parse(&args("offset=10")).unwrap().apply(files);
+        assert!(page.is_empty());
+        assert_eq!(total, 2);
     }
 }
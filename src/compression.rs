@@ -0,0 +1,127 @@
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::fs::FileExt;
+
+/// Compression files can be stored with
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Gzip,
+    Zstd,
+}
+
+impl Encoding {
+    pub fn parse(value: &str) -> Option<Encoding> {
+        match value.to_lowercase().as_str() {
+            "gzip" => Some(Encoding::Gzip),
+            "zstd" => Some(Encoding::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    /// Bytes every file stored with this encoding starts with
+    fn magic(self) -> &'static [u8] {
+        match self {
+            Encoding::Gzip => &[0x1f, 0x8b],
+            Encoding::Zstd => &[0x28, 0xb5, 0x2f, 0xfd],
+        }
+    }
+
+    /// Whether a file starts like one stored with this encoding, used to tell whether a file
+    /// changed on disk is still stored compressed. Doesn't move the file's read position.
+    pub fn matches(self, file: &File) -> bool {
+        let magic = self.magic();
+        let mut start = [0_u8; 4];
+        let start = &mut start[..magic.len()];
+
+        file.read_exact_at(start, 0).is_ok() && start == magic
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// How a content is stored on disk, `size` is the size of the content itself
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stored {
+    pub encoding: Option<Encoding>,
+    pub size: u64,
+}
+
+/// Writes content to a file the way it is stored, compressed or as is
+pub enum StoreWriter {
+    Plain(File),
+    Gzip(GzEncoder<File>),
+    Zstd(zstd::Encoder<'static, File>),
+}
+
+impl StoreWriter {
+    /// # Examples
+    /// ```
+    /// let mut writer = StoreWriter::new(File::create(path)?, Some(Encoding::Zstd))?;
+    /// writer.write_all(b"hello")?;
+    /// writer.finish()?.sync_all()?;
+    /// ```
+    pub fn new(file: File, encoding: Option<Encoding>) -> io::Result<StoreWriter> {
+        Ok(match encoding {
+            None => StoreWriter::Plain(file),
+            Some(Encoding::Gzip) => StoreWriter::Gzip(GzEncoder::new(file, Default::default())),
+            Some(Encoding::Zstd) => StoreWriter::Zstd(zstd::Encoder::new(file, 0)?),
+        })
+    }
+
+    /// End the compressed stream, the file is complete afterwards
+    pub fn finish(self) -> io::Result<File> {
+        match self {
+            StoreWriter::Plain(file) => Ok(file),
+            StoreWriter::Gzip(encoder) => encoder.finish(),
+            StoreWriter::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl Write for StoreWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            StoreWriter::Plain(file) => file.write(buf),
+            StoreWriter::Gzip(encoder) => encoder.write(buf),
+            StoreWriter::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            StoreWriter::Plain(file) => file.flush(),
+            StoreWriter::Gzip(encoder) => encoder.flush(),
+            StoreWriter::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Read the content of a stored file, decompressing it if it is stored compressed
+/// # Examples
+/// ```
+/// let hash = hash::digest(&mut compression::reader(file, indexed.encoding)?, algorithm)?;
+/// ```
+pub fn reader(file: File, encoding: Option<Encoding>) -> io::Result<Box<dyn Read + Send>> {
+    Ok(match encoding {
+        None => Box::new(file),
+        Some(Encoding::Gzip) => Box::new(GzDecoder::new(file)),
+        Some(Encoding::Zstd) => Box::new(zstd::Decoder::new(file)?),
+    })
+}
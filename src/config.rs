@@ -0,0 +1,178 @@
+use crate::compression::Encoding;
+use crate::hash::Algorithm;
+use crate::ip_filter::Cidr;
+
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use slog::Logger;
+use slog::*;
+
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+lazy_static! {
+    static ref CONFIG: Mutex<Option<Arc<Config>>> = Mutex::new(None);
+}
+
+pub const CONFIG_FILE: &str = "server.toml";
+
+/// Server configuration, read from `server.toml` in the working directory.
+/// Every key is optional, missing keys fall back to the defaults below.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Address the AFTP listener binds to
+    pub listen: String,
+    /// Address ranges clients may connect from, any address when empty
+    pub allow: Vec<Cidr>,
+    /// Address ranges refused even when allowed
+    pub deny: Vec<Cidr>,
+    /// Seconds a keep-alive connection may stay silent between requests
+    pub idle_timeout_secs: u64,
+    /// Seconds a read may block halfway through a request or upload
+    pub read_timeout_secs: u64,
+    /// Seconds a write may block while sending a response
+    pub write_timeout_secs: u64,
+    /// Number of worker threads serving connections
+    pub worker_threads: usize,
+    /// Accepted connections waiting for a free worker, beyond this clients get a busy status
+    pub accept_queue: usize,
+    /// Seconds active transfers get to finish after SIGINT/SIGTERM
+    pub shutdown_timeout_secs: u64,
+    /// Reject every command except LOGIN and QUIT until the client has logged in
+    pub require_login: bool,
+    /// Algorithm files are hashed with when indexed, and PUT hashes default to
+    pub hash_algorithm: Algorithm,
+    /// Threads hashing files during the startup scan, one per core when unset
+    pub index_threads: Option<usize>,
+    /// Serve requests while the startup scan is running, files not indexed yet are reported
+    /// as still indexing
+    pub background_indexing: bool,
+    /// Keep the index in sync with files other processes change in the file root
+    pub watch_files: bool,
+    /// Seconds between integrity scrubs re-hashing every file, 0 turns scrubbing off
+    pub scrub_interval_secs: u64,
+    /// Earlier versions kept per file when it is replaced, 0 turns versioning off
+    pub keep_versions: usize,
+    /// Seconds an earlier version is kept, for as long as `keep_versions` allows when unset
+    pub version_retention_secs: Option<u64>,
+    /// Seconds deleted files stay in the trash before they are purged, 0 removes them directly
+    pub trash_retention_secs: u64,
+    /// Store each distinct content once, files with the same content share it
+    pub dedup: bool,
+    /// Compression new uploads are stored with, stored as is when unset
+    pub compression: Option<Encoding>,
+    /// Largest file a PUT may upload, unlimited when unset
+    pub max_upload_bytes: Option<u64>,
+    /// Space to keep free on the storage filesystem, uploads that would eat into it are refused
+    pub min_free_bytes: u64,
+    /// File with the key bearer tokens are signed with, tokens are rejected when unset
+    pub token_key_file: Option<String>,
+    /// Per client address limits
+    pub limits: LimitsConfig,
+    /// Serve TLS instead of plain TCP when present
+    pub tls: Option<TlsConfig>,
+}
+
+/// The `[limits]` section of the configuration, every limit is off when unset
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// Connections a single address may have open at once
+    pub max_connections_per_ip: Option<usize>,
+    /// Sustained requests per second from a single address, bursts of up to a second's worth
+    pub requests_per_sec: Option<f64>,
+    /// Rate a single GET response is sent at
+    pub get_bytes_per_sec: Option<u64>,
+    /// Rate a single PUT body is received at
+    pub put_bytes_per_sec: Option<u64>,
+}
+
+/// The `[tls]` section of the configuration
+#[derive(Debug, Deserialize)]
+pub struct TlsConfig {
+    /// PEM file with the server certificate chain
+    pub cert_file: String,
+    /// PEM file with the private key of the server certificate
+    pub key_file: String,
+    /// PEM file with the CA certificates client certificates are verified against
+    pub client_ca_file: Option<String>,
+    /// Refuse clients without a certificate signed by `client_ca_file`
+    #[serde(default)]
+    pub require_client_cert: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listen: "127.0.0.1:9123".to_string(),
+            allow: Vec::new(),
+            deny: Vec::new(),
+            idle_timeout_secs: 300,
+            read_timeout_secs: 30,
+            write_timeout_secs: 30,
+            worker_threads: 16,
+            accept_queue: 64,
+            shutdown_timeout_secs: 30,
+            require_login: true,
+            hash_algorithm: Algorithm::Md5,
+            index_threads: None,
+            background_indexing: false,
+            watch_files: true,
+            scrub_interval_secs: 86400,
+            keep_versions: 10,
+            version_retention_secs: None,
+            trash_retention_secs: 604800,
+            dedup: false,
+            compression: None,
+            max_upload_bytes: None,
+            min_free_bytes: 0,
+            token_key_file: None,
+            limits: LimitsConfig::default(),
+            tls: None,
+        }
+    }
+}
+
+impl Config {
+    /// Load the configuration file, should be called once at startup:
+    /// # Examples
+    /// ```
+    /// Config::initialize(CONFIG_FILE, log.clone());
+    /// ```
+    pub fn initialize(path: &str, log: Logger) {
+        let config = match fs::read_to_string(path) {
+            Ok(content) => match toml::from_str(&content) {
+                Ok(config) => {
+                    info!(log, "Loaded configuration from {}", path);
+                    config
+                }
+                Err(e) => {
+                    crit!(log, "Invalid configuration in {}: {}", path, e);
+                    panic!("Invalid configuration in {}: {}", path, e);
+                }
+            },
+            Err(_) => {
+                info!(
+                    log,
+                    "No configuration file found at {}, using defaults", path
+                );
+                Config::default()
+            }
+        };
+
+        *CONFIG.lock().unwrap() = Some(Arc::new(config));
+    }
+
+    /// Get the loaded configuration
+    /// # Examples
+    /// ```
+    /// let idle_timeout = Config::get().idle_timeout_secs;
+    /// ```
+    pub fn get() -> Arc<Config> {
+        match CONFIG.lock().unwrap().as_ref() {
+            Some(config) => config.clone(),
+            None => panic!("Configuration must be initialized before use"),
+        }
+    }
+}
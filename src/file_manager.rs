@@ -6,39 +6,119 @@ extern crate slog_term;
 // date time library
 extern crate chrono;
 
+use crate::blobs::Blobs;
+use crate::compression;
+use crate::compression::{Encoding, Stored};
+use crate::config::Config;
+use crate::hash;
+use crate::hash::Algorithm;
+
 use chrono::{DateTime, Utc};
 use lazy_static::lazy_static;
 use slog::Logger;
 use slog::*;
 
 use rusqlite::{params, Connection};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::ffi::CString;
 use std::fs::File;
+use std::io;
 use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Mutex;
+use std::thread;
+use std::time::UNIX_EPOCH;
 use std::{env, fs};
 
 lazy_static! {
     pub static ref SINGLETON: Mutex<Option<FileManager>> = Mutex::new(None);
 }
 
-const FILE_ROOT: &str = "./server_files";
+pub const FILE_ROOT: &str = "./server_files";
+pub const DATABASE: &str = "files.db";
+// uploads in progress, moved into FILE_ROOT once complete
+const UPLOAD_ROOT: &str = "./uploads";
+
+static UPLOAD_COUNTER: AtomicUsize = AtomicUsize::new(0);
+static INDEXING: AtomicBool = AtomicBool::new(false);
+
+/// Modification time of a file in nanoseconds, compared to tell whether it changed
+fn modified_nanos(metadata: &fs::Metadata) -> i64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |modified| modified.as_nanos() as i64)
+}
+
+/// Whether a file on disk is still the one indexed with this size and modification time. A
+/// compressed file differs in size from its content, only the modification time tells.
+fn unchanged(
+    size: u64,
+    encoding: Option<Encoding>,
+    modified: i64,
+    metadata: &fs::Metadata,
+) -> bool {
+    (encoding.is_some() || size == metadata.len()) && modified == modified_nanos(metadata)
+}
+
+/// Counts the bytes read through it
+struct Counted<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.inner.read(buf)?;
+        self.count += size as u64;
+        Ok(size)
+    }
+}
+
+/// Add a column to a table created by an older version
+pub(crate) fn add_column(conn: &Connection, table: &str, name: &str, definition: &str) {
+    if conn
+        .prepare(&format!("SELECT {} FROM {} LIMIT 0", name, table))
+        .is_err()
+    {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, name, definition),
+            params![],
+        )
+        .unwrap();
+    }
+}
 
-/// MD5_Digest for File:
-fn md5_digest(mut filename: File) -> String {
-    let mut data = Vec::new();
-    filename.read_to_end(&mut data).unwrap();
-    let digest = md5::compute(data);
-    let md5_hash = format!("{:#X}", digest);
-    md5_hash
+/// A file as recorded in the database by the last flush
+struct Persisted {
+    hash: String,
+    algorithm: Algorithm,
+    size: u64,
+    modified: i64,
+    encoding: Option<Encoding>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct TFile {
     pub(crate) filename: String,
+    #[serde(skip)]
     pub(crate) path: String,
     pub(crate) hash: String,
+    /// Algorithm `hash` was computed with
+    pub(crate) algorithm: Algorithm,
     pub(crate) created: i64,
+    pub(crate) size: u64,
+    /// Modification time in nanoseconds when indexed
+    #[serde(skip)]
+    pub(crate) modified: i64,
     pub(crate) locked: bool,
+    /// Compression the file is stored with, `hash` and `size` describe the content itself
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) encoding: Option<Encoding>,
 }
 
 // PartialEQ for TFile, able to check if hash matches
@@ -49,16 +129,27 @@ impl PartialEq for TFile {
 }
 
 impl TFile {
-    pub fn new_file(file: File, filename: String, _path: String, _hash: String) -> TFile {
-        let _created = file.metadata().unwrap().created().unwrap();
+    pub fn new_file(
+        file: File,
+        filename: String,
+        _path: String,
+        _hash: String,
+        algorithm: Algorithm,
+    ) -> TFile {
+        let metadata = file.metadata().unwrap();
+        let _created = metadata.created().unwrap();
         let converted_datetime = DateTime::<Utc>::from(_created).timestamp();
 
         TFile {
             filename,
             path: _path,
             hash: _hash,
+            algorithm,
             created: converted_datetime,
+            size: metadata.len(),
+            modified: modified_nanos(&metadata),
             locked: false,
+            encoding: None,
         }
     }
 }
@@ -76,7 +167,7 @@ impl FileManager {
     /// ```
     pub fn initialize(log: Logger) {
         info!(log, "Initializing file manager");
-        let conn = Connection::open("files.db").unwrap();
+        let conn = Connection::open(DATABASE).unwrap();
 
         conn.execute(
             "CREATE TABLE IF NOT EXISTS file (
@@ -90,6 +181,13 @@ impl FileManager {
         )
         .unwrap();
 
+        // indexes from before hash algorithms were recorded only hold MD5 hashes
+        add_column(&conn, "file", "algorithm", "TEXT NOT NULL DEFAULT 'md5'");
+        // unknown for older indexes, so their files get hashed again
+        add_column(&conn, "file", "size", "INTEGER");
+        add_column(&conn, "file", "modified", "INTEGER");
+        add_column(&conn, "file", "encoding", "TEXT");
+
         let mut st = SINGLETON.lock().unwrap();
         let vec = Mutex::new(Vec::new());
         if st.is_none() {
@@ -112,7 +210,7 @@ impl FileManager {
         }
     }
 
-    /// Create file with file manager
+    /// Create file with file manager, replaces the entry of an existing file with the same name
     /// # Examples
     /// ```
     /// FileManager::get().lock().unwrap().as_mut().unwrap().create(
@@ -120,21 +218,172 @@ impl FileManager {
     ///     file_name.unwrap().to_string(),
     ///     file_path.to_string(),
     ///     hash.to_string(),
+    ///     Algorithm::Sha256,
     /// );
     /// ```
-    pub fn create(&mut self, file: File, file_name: String, path: String, hash: String) -> bool {
+    pub fn create(
+        &mut self,
+        file: File,
+        file_name: String,
+        path: String,
+        hash: String,
+        algorithm: Algorithm,
+    ) -> bool {
+        let mut files = self.files.lock().unwrap();
+        files.retain(|f| f.filename != file_name);
+        files.push(TFile::new_file(file, file_name, path, hash, algorithm));
+        true
+    }
+
+    /// Record that a file is stored compressed, `create` indexes it as stored
+    pub fn set_stored(&mut self, file_name: &str, stored: Stored) -> bool {
+        let mut files = self.files.lock().unwrap();
+
+        for file in files.iter_mut() {
+            if file.filename == file_name {
+                file.encoding = stored.encoding;
+                file.size = stored.size;
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Remove a file from the index, the file on disk is left untouched
+    pub fn remove(&mut self, file_name: &str) -> bool {
+        let mut files = self.files.lock().unwrap();
+        let count = files.len();
+        files.retain(|f| f.filename != file_name);
+        files.len() != count
+    }
+
+    /// Find a file by name
+    /// # Examples
+    /// ```
+    /// let file = instance.as_ref().unwrap().find("notes.txt");
+    /// ```
+    pub fn find(&self, file_name: &str) -> Option<TFile> {
         self.files
             .lock()
             .unwrap()
-            .push(TFile::new_file(file, file_name, path, hash));
-        true
+            .iter()
+            .find(|f| f.filename == file_name)
+            .cloned()
+    }
+
+    /// Any indexed file with this content whose name passes `filter`
+    pub fn find_content(
+        &self,
+        algorithm: Algorithm,
+        hash: &str,
+        filter: impl Fn(&str) -> bool,
+    ) -> Option<TFile> {
+        self.files
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|f| {
+                f.algorithm == algorithm && f.hash.eq_ignore_ascii_case(hash) && filter(&f.filename)
+            })
+            .cloned()
+    }
+
+    /// File names are flat, they may not point outside of the file root
+    pub fn is_valid_name(file_name: &str) -> bool {
+        !file_name.is_empty()
+            && file_name != "."
+            && file_name != ".."
+            && !file_name.contains(['/', '\\', '\0'])
+    }
+
+    /// Path of a file with the given name in the file root
+    pub fn file_path(file_name: &str) -> PathBuf {
+        env::current_dir().unwrap().join(FILE_ROOT).join(file_name)
+    }
+
+    /// Unique path to receive an upload for the given file name in
+    pub fn upload_path(file_name: &str) -> PathBuf {
+        let id = UPLOAD_COUNTER.fetch_add(1, Ordering::SeqCst);
+        env::current_dir()
+            .unwrap()
+            .join(UPLOAD_ROOT)
+            .join(format!("{}.{}.part", file_name, id))
+    }
+
+    /// Bytes available to the server on the filesystem holding the file root and uploads
+    pub fn free_space() -> io::Result<u64> {
+        let root = env::current_dir()?.join(FILE_ROOT);
+        let path = CString::new(root.as_os_str().as_bytes())?;
+
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+    }
+
+    /// Remove uploads that never completed, and create the upload directory if needed
+    /// # Examples
+    /// ```
+    /// FileManager::clean_uploads(log.clone());
+    /// ```
+    pub fn clean_uploads(log: Logger) {
+        let upload_root = env::current_dir().unwrap().join(UPLOAD_ROOT);
+
+        if let Ok(paths) = fs::read_dir(&upload_root) {
+            for path in paths.flatten() {
+                info!(log, "Discarding incomplete upload: {:?}", path.path());
+                let _ = fs::remove_file(path.path());
+            }
+        }
+
+        if let Err(e) = fs::create_dir_all(&upload_root) {
+            error!(log, "Error occurred: {:?}", e);
+        }
+    }
+
+    /// Write the current index to the database, replacing what was stored before
+    /// # Examples
+    /// ```
+    /// FileManager::get().lock().unwrap().as_mut().unwrap().flush(log.clone());
+    /// ```
+    pub fn flush(&mut self, log: Logger) {
+        let files = self.list();
+        let mut conn = Connection::open(DATABASE).unwrap();
+        let tx = conn.transaction().unwrap();
+
+        tx.execute("DELETE FROM file", params![]).unwrap();
+
+        for file in &files {
+            tx.execute(
+                "INSERT INTO file
+                      (filename, path, hash, algorithm, size, modified, locked, encoding)
+                      VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    file.filename,
+                    file.path,
+                    file.hash,
+                    file.algorithm.name(),
+                    file.size as i64,
+                    file.modified,
+                    file.locked,
+                    file.encoding.map(Encoding::name)
+                ],
+            )
+            .unwrap();
+        }
+
+        tx.commit().unwrap();
+        info!(log, "Flushed {} files to the index", files.len());
     }
 
     pub fn lock_file(&mut self, file_name: &str, lock: bool) -> bool {
-        let files = self.files.lock().unwrap().to_vec();
+        let mut files = self.files.lock().unwrap();
 
-        for mut file in files {
-            if file.filename == file_name.to_string() {
+        for file in files.iter_mut() {
+            if file.filename == file_name {
                 file.locked = lock;
                 return true;
             }
@@ -144,9 +393,9 @@ impl FileManager {
     }
 
     pub fn unlock_all_files(&mut self) {
-        let files = self.files.lock().unwrap().to_vec();
+        let mut files = self.files.lock().unwrap();
 
-        for mut file in files {
+        for file in files.iter_mut() {
             file.locked = false;
         }
     }
@@ -159,17 +408,22 @@ impl FileManager {
         self.files.lock().unwrap().to_vec()
     }
 
-    /// Get files from file manager
+    /// Whether the startup scan is still running, files it hasn't reached yet are missing
+    /// from the index
+    pub fn is_indexing() -> bool {
+        INDEXING.load(Ordering::SeqCst)
+    }
+
+    /// Index the files in the file root. Files whose size and modification time match the
+    /// persisted index keep their hash, the others are hashed in parallel. With `background`
+    /// the scan runs on its own thread and this returns at once.
     /// # Examples
     /// ```
-    /// FileManager::get()
-    /// .lock()
-    /// .unwrap()
-    /// .as_mut()
-    /// .unwrap()
-    /// .get_files(log.clone());
+    /// FileManager::index(log.clone(), Config::get().background_indexing);
     /// ```
-    pub fn get_files(&mut self, log: Logger) {
+    pub fn index(log: Logger, background: bool) {
+        INDEXING.store(true, Ordering::SeqCst);
+
         let mut root_path = env::current_dir().unwrap();
         root_path = root_path.join(FILE_ROOT);
 
@@ -180,7 +434,12 @@ impl FileManager {
 
             match create_dir {
                 Ok(create_dir) => {
-                    info!(log, "Created directory: {:?}: {:?}", create_dir, root_path.as_path());
+                    info!(
+                        log,
+                        "Created directory: {:?}: {:?}",
+                        create_dir,
+                        root_path.as_path()
+                    );
                 }
                 Err(e) => {
                     error!(log, "Error occurred: {:?}", e);
@@ -188,47 +447,327 @@ impl FileManager {
             }
         }
 
-        let paths = fs::read_dir(root_path.as_path()).unwrap();
+        if background {
+            info!(log, "Indexing files in the background");
+            thread::spawn(move || FileManager::get_files(log));
+        } else {
+            FileManager::get_files(log);
+        }
+    }
 
-        for path in paths {
-            let file_name = path.as_ref().unwrap().file_name().into_string().unwrap();
-            let current_path = path
-                .as_ref()
-                .unwrap()
-                .path()
-                .into_os_string()
-                .into_string()
-                .unwrap();
+    fn get_files(log: Logger) {
+        let root_path = env::current_dir().unwrap().join(FILE_ROOT);
 
-            let file = File::open(path.unwrap().path()).unwrap();
-            let _file = file.try_clone().unwrap();
-            let md5_hash = md5_digest(file);
+        let algorithm = Config::get().hash_algorithm;
+        let persisted = FileManager::persisted();
+        let mut pending = Vec::new();
+        let mut reused = 0;
 
-            info!(log, "MD5 hash is {:?}", md5_hash);
-            info!(log, "Path: {:?}", current_path);
+        for path in fs::read_dir(root_path.as_path()).unwrap().flatten() {
+            let file_name = match path.file_name().into_string() {
+                Ok(file_name) => file_name,
+                Err(name) => {
+                    warn!(log, "Skipping file with a non UTF-8 name: {:?}", name);
+                    continue;
+                }
+            };
+            let current_path = path.path().to_string_lossy().to_string();
 
-            let current_file = TFile {
-                filename: (&file_name).parse().unwrap(),
-                path: (&current_path).to_string(),
-                hash: (&md5_hash).parse().unwrap(),
-                created: 0,
-                locked: false,
+            let metadata = match path.metadata() {
+                Ok(metadata) if metadata.is_file() => metadata,
+                _ => continue,
             };
 
-            let conn = Connection::open("files.db").unwrap();
+            let previous = persisted.get(&file_name);
+            let stored = previous.filter(|stored| {
+                unchanged(stored.size, stored.encoding, stored.modified, &metadata)
+            });
+
+            // unchanged since the last run, the stored hash is still good
+            match stored {
+                Some(stored) if stored.algorithm == algorithm => {
+                    if let Ok(file) = File::open(&current_path) {
+                        FileManager::add_indexed(
+                            file,
+                            file_name,
+                            current_path,
+                            stored.hash.clone(),
+                            Some(Stored {
+                                encoding: stored.encoding,
+                                size: stored.size,
+                            }),
+                        );
+                        reused += 1;
+                    }
+                }
+                // changed on disk or hashed with another algorithm, a file that was stored
+                // compressed is checked for still being so
+                _ => pending.push((
+                    file_name,
+                    current_path,
+                    previous.and_then(|stored| stored.encoding),
+                )),
+            }
+        }
 
-            conn.execute(
-                "INSERT INTO file (filename, path, hash, locked) VALUES (?1, ?2, ?3, ?4)",
-                params![
-                    current_file.filename,
-                    current_file.path,
-                    current_file.hash,
-                    current_file.locked
-                ],
+        info!(
+            log,
+            "{} files unchanged, hashing {} with {}",
+            reused,
+            pending.len(),
+            algorithm
+        );
+
+        let threads = Config::get()
+            .index_threads
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()))
+            .max(1);
+        let next = AtomicUsize::new(0);
+
+        thread::scope(|scope| {
+            for _ in 0..threads.min(pending.len()) {
+                scope.spawn(|| {
+                    while let Some((file_name, current_path, encoding)) =
+                        pending.get(next.fetch_add(1, Ordering::SeqCst))
+                    {
+                        match FileManager::hash_content(current_path, *encoding, algorithm) {
+                            Ok((file, hash, stored)) => {
+                                info!(log, "{} hash of {} is {:?}", algorithm, file_name, hash);
+                                FileManager::add_indexed(
+                                    file,
+                                    file_name.clone(),
+                                    current_path.clone(),
+                                    hash,
+                                    stored,
+                                );
+                            }
+                            Err(e) => error!(log, "Failed to hash {}: {}", current_path, e),
+                        }
+                    }
+                });
+            }
+        });
+
+        INDEXING.store(false, Ordering::SeqCst);
+
+        let mut instance = FileManager::get().lock().unwrap();
+        let manager = instance.as_mut().unwrap();
+        info!(log, "Indexed {} files", manager.list().len());
+        manager.flush(log.clone());
+    }
+
+    /// Add a file found by the startup scan, unless a request has changed it in the meantime.
+    /// `stored` is how a file kept from the last run is stored.
+    fn add_indexed(
+        file: File,
+        file_name: String,
+        path: String,
+        hash: String,
+        stored: Option<Stored>,
+    ) {
+        let mut instance = FileManager::get().lock().unwrap();
+        let manager = instance.as_mut().unwrap();
+
+        // uploaded while indexing, or deleted after being hashed
+        if manager.find(&file_name).is_some() || fs::metadata(&path).is_err() {
+            return;
+        }
+
+        let algorithm = Config::get().hash_algorithm;
+        manager.create(file, file_name.clone(), path, hash, algorithm);
+        if let Some(stored) = stored {
+            manager.set_stored(&file_name, stored);
+        }
+    }
+
+    /// Hash the content of a file last known to be stored with `encoding`. A file that doesn't
+    /// start like that encoding anymore has been replaced by plain content. Returns how a file
+    /// that is still compressed is stored, the hash is of the content either way.
+    fn hash_content(
+        path: impl AsRef<Path>,
+        encoding: Option<Encoding>,
+        algorithm: Algorithm,
+    ) -> io::Result<(File, String, Option<Stored>)> {
+        let file = File::open(path)?;
+
+        match encoding.filter(|encoding| encoding.matches(&file)) {
+            Some(encoding) => {
+                let mut content = compression::reader(file.try_clone()?, Some(encoding))?;
+                let mut counted = Counted {
+                    inner: &mut content,
+                    count: 0,
+                };
+                let hash = hash::digest(&mut counted, algorithm)?;
+                let stored = Stored {
+                    encoding: Some(encoding),
+                    size: counted.count,
+                };
+                Ok((file, hash, Some(stored)))
+            }
+            None => {
+                let hash = hash::digest(&mut &file, algorithm)?;
+                Ok((file, hash, None))
+            }
+        }
+    }
+
+    /// Bring the index entry of a file in line with the file root after it was changed on
+    /// disk, by this server or any other process. A file that is gone is removed, a new or
+    /// changed one is hashed again.
+    /// # Examples
+    /// ```
+    /// FileManager::sync_file("report.csv", log.clone());
+    /// ```
+    pub fn sync_file(file_name: &str, log: Logger) {
+        let path = FileManager::file_path(file_name);
+        let indexed = FileManager::get()
+            .lock()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .find(file_name);
+
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => {
+                if let Some(indexed) = indexed {
+                    info!(log, "File removed from the file root: {}", file_name);
+                    let mut instance = FileManager::get().lock().unwrap();
+                    instance.as_mut().unwrap().remove(file_name);
+                    drop(instance);
+                    FileManager::forget(file_name);
+                    Blobs::release(indexed.algorithm, &indexed.hash, log);
+                }
+                return;
+            }
+        };
+
+        // an upload of this server, already indexed with its hash
+        if let Some(indexed) = &indexed {
+            if unchanged(indexed.size, indexed.encoding, indexed.modified, &metadata) {
+                return;
+            }
+        }
+
+        let algorithm = Config::get().hash_algorithm;
+        let encoding = indexed.as_ref().and_then(|indexed| indexed.encoding);
+
+        let (file, hash, stored) = match FileManager::hash_content(&path, encoding, algorithm) {
+            Ok(hashed) => hashed,
+            Err(e) => {
+                error!(log, "Failed to hash {:?}: {}", path, e);
+                return;
+            }
+        };
+
+        info!(
+            log,
+            "File changed in the file root: {}, {} hash is {:?}", file_name, algorithm, hash
+        );
+
+        let mut instance = FileManager::get().lock().unwrap();
+        let manager = instance.as_mut().unwrap();
+
+        // indexed by an upload while this was hashed
+        let current = manager.find(file_name);
+        if current.as_ref().is_some_and(|current| {
+            unchanged(current.size, current.encoding, current.modified, &metadata)
+        }) {
+            return;
+        }
+
+        let locked = current.is_some_and(|f| f.locked);
+        manager.create(
+            file,
+            file_name.to_string(),
+            path.to_string_lossy().to_string(),
+            hash.clone(),
+            algorithm,
+        );
+        if let Some(stored) = stored {
+            manager.set_stored(file_name, stored);
+        }
+        // a lock held by a connection survives the change
+        manager.lock_file(file_name, locked);
+        let file = manager.find(file_name).unwrap();
+        drop(instance);
+
+        FileManager::store(&file);
+
+        // replaced by another process, the old content may not be referenced anymore
+        if let Some(indexed) = indexed {
+            if indexed.hash != hash || indexed.algorithm != algorithm {
+                Blobs::release(indexed.algorithm, &indexed.hash, log);
+            }
+        }
+    }
+
+    /// Write a single index entry to the database, so it survives the server stopping without
+    /// a flush
+    pub fn store(file: &TFile) {
+        let mut conn = Connection::open(DATABASE).unwrap();
+        let tx = conn.transaction().unwrap();
+
+        tx.execute(
+            "DELETE FROM file WHERE filename = ?1",
+            params![file.filename],
+        )
+        .unwrap();
+        tx.execute(
+            "INSERT INTO file
+                  (filename, path, hash, algorithm, size, modified, locked, encoding)
+                  VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                file.filename,
+                file.path,
+                file.hash,
+                file.algorithm.name(),
+                file.size as i64,
+                file.modified,
+                file.locked,
+                file.encoding.map(Encoding::name)
+            ],
+        )
+        .unwrap();
+
+        tx.commit().unwrap();
+    }
+
+    /// Remove a single index entry from the database
+    fn forget(file_name: &str) {
+        let conn = Connection::open(DATABASE).unwrap();
+        conn.execute("DELETE FROM file WHERE filename = ?1", params![file_name])
+            .unwrap();
+    }
+
+    /// Hashes stored by the last flush, by file name
+    fn persisted() -> HashMap<String, Persisted> {
+        let conn = Connection::open(DATABASE).unwrap();
+        let mut statement = conn
+            .prepare(
+                "SELECT filename, hash, algorithm, size, modified, encoding FROM file
+                  WHERE size IS NOT NULL AND modified IS NOT NULL",
             )
             .unwrap();
 
-            self.create(_file, file_name, current_path, md5_hash);
-        }
+        let rows = statement
+            .query_map(params![], |row| {
+                let algorithm: String = row.get(2)?;
+                let size: i64 = row.get(3)?;
+                let encoding: Option<String> = row.get(5)?;
+                Ok((
+                    row.get::<_, String>(0)?,
+                    Persisted {
+                        hash: row.get(1)?,
+                        algorithm: Algorithm::parse(&algorithm).unwrap_or(Algorithm::Md5),
+                        size: size as u64,
+                        modified: row.get(4)?,
+                        encoding: encoding.as_deref().and_then(Encoding::parse),
+                    },
+                ))
+            })
+            .unwrap();
+
+        rows.filter_map(|row| row.ok()).collect()
     }
 }
@@ -0,0 +1,242 @@
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::io::Read;
+
+// chunk size for hashing files, memory use doesn't grow with the file size
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Hash algorithms file contents can be identified by
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    Md5,
+    Sha256,
+    Sha512,
+}
+
+impl Algorithm {
+    pub fn parse(value: &str) -> Option<Algorithm> {
+        match value.to_lowercase().as_str() {
+            "md5" => Some(Algorithm::Md5),
+            "sha256" | "sha-256" => Some(Algorithm::Sha256),
+            "sha512" | "sha-512" => Some(Algorithm::Sha512),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "md5",
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Sha512 => "sha512",
+        }
+    }
+
+    /// Whether `hash` looks like a hex digest of this algorithm
+    pub fn is_valid_hash(self, hash: &str) -> bool {
+        let length = match self {
+            Algorithm::Md5 => 16,
+            Algorithm::Sha256 => digest::SHA256_OUTPUT_LEN,
+            Algorithm::Sha512 => digest::SHA512_OUTPUT_LEN,
+        };
+        hash.len() == length * 2 && hash.chars().all(|c| c.is_ascii_hexdigit())
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+enum Context {
+    Md5(md5::Context),
+    Ring(digest::Context),
+}
+
+/// Computes a digest from data fed to it piece by piece
+pub struct Hasher {
+    context: Context,
+}
+
+impl Hasher {
+    /// # Examples
+    /// ```
+    /// let mut hasher = Hasher::new(Algorithm::Sha256);
+    /// hasher.update(b"hello");
+    /// let hash = hasher.finish();
+    /// ```
+    pub fn new(algorithm: Algorithm) -> Hasher {
+        let context = match algorithm {
+            Algorithm::Md5 => Context::Md5(md5::Context::new()),
+            Algorithm::Sha256 => Context::Ring(digest::Context::new(&digest::SHA256)),
+            Algorithm::Sha512 => Context::Ring(digest::Context::new(&digest::SHA512)),
+        };
+
+        Hasher { context }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match &mut self.context {
+            Context::Md5(context) => context.consume(data),
+            Context::Ring(context) => context.update(data),
+        }
+    }
+
+    /// The digest as upper case hex
+    pub fn finish(self) -> String {
+        match self.context {
+            Context::Md5(context) => format!("{:X}", context.compute()),
+            Context::Ring(context) => context
+                .finish()
+                .as_ref()
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect(),
+        }
+    }
+}
+
+/// Hash everything a reader yields, in fixed-size chunks
+/// # Examples
+/// ```
+/// let hash = hash::digest(&mut File::open(path)?, Algorithm::Sha256)?;
+/// ```
+pub fn digest(reader: &mut impl Read, algorithm: Algorithm) -> io::Result<String> {
+    let mut hasher = Hasher::new(algorithm);
+    let mut buf = vec![0_u8; CHUNK_SIZE];
+
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok(hasher.finish()),
+            Ok(size) => hasher.update(&buf[..size]),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // known answers from RFC 1321 and FIPS 180-2
+    const VECTORS: &[(Algorithm, &[u8], &str)] = &[
+        (Algorithm::Md5, b"", "D41D8CD98F00B204E9800998ECF8427E"),
+        (Algorithm::Md5, b"abc", "900150983CD24FB0D6963F7D28E17F72"),
+        (
+            Algorithm::Sha256,
+            b"",
+            "E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855",
+        ),
+        (
+            Algorithm::Sha256,
+            b"abc",
+            "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD",
+        ),
+        (
+            Algorithm::Sha512,
+            b"",
+            "CF83E1357EEFB8BDF1542850D66D8007D620E4050B5715DC83F4A921D36CE9CE\
+             47D0D13C5D85F2B0FF8318D2877EEC2F63B931BD47417A81A538327AF927DA3E",
+        ),
+        (
+            Algorithm::Sha512,
+            b"abc",
+            "DDAF35A193617ABACC417349AE20413112E6FA4E89A97EA20A9EEEE64B55D39A\
+             2192992A274FC1A836BA3C23A3FEEBBD454D4423643CE80E2A9AC94FA54CA49F",
+        ),
+    ];
+
+    // yields at most a few bytes per read and is interrupted now and then
+    struct Trickle<'a> {
+        data: &'a [u8],
+        reads: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            if self.reads.is_multiple_of(7) {
+                return Err(io::Error::from(io::ErrorKind::Interrupted));
+            }
+
+            let size = buf.len().min(self.data.len()).min(1000 + self.reads % 3);
+            buf[..size].copy_from_slice(&self.data[..size]);
+            self.data = &self.data[size..];
+            Ok(size)
+        }
+    }
+
+    #[test]
+    fn hasher_matches_known_answers() {
+        for (algorithm, data, expected) in VECTORS {
+            let mut hasher = Hasher::new(*algorithm);
+            hasher.update(data);
+            assert_eq!(hasher.finish(), *expected, "{}", algorithm);
+        }
+    }
+
+    #[test]
+    fn hasher_takes_data_piece_by_piece() {
+        for (algorithm, data, expected) in VECTORS {
+            let mut hasher = Hasher::new(*algorithm);
+            for byte in data.chunks(1) {
+                hasher.update(byte);
+            }
+            assert_eq!(hasher.finish(), *expected, "{}", algorithm);
+        }
+    }
+
+    #[test]
+    fn valid_hashes_have_the_algorithms_length() {
+        for (algorithm, _, expected) in VECTORS {
+            assert!(algorithm.is_valid_hash(expected));
+            assert!(algorithm.is_valid_hash(&expected.to_lowercase()));
+            assert!(!algorithm.is_valid_hash(&expected[1..]));
+            assert!(!algorithm.is_valid_hash(&format!("{}0", expected)));
+            assert!(!algorithm.is_valid_hash(&format!("{}G", &expected[1..])));
+        }
+
+        assert!(!Algorithm::Sha256.is_valid_hash(VECTORS[0].2));
+        assert!(!Algorithm::Md5.is_valid_hash(""));
+    }
+
+    #[test]
+    fn digest_over_many_chunks_matches_a_single_update() {
+        let data: Vec<u8> = (0..3 * CHUNK_SIZE + 123).map(|i| (i % 251) as u8).collect();
+
+        for algorithm in &[Algorithm::Md5, Algorithm::Sha256, Algorithm::Sha512] {
+            let mut hasher = Hasher::new(*algorithm);
+            hasher.update(&data);
+            let expected = hasher.finish();
+
+            assert_eq!(digest(&mut &data[..], *algorithm).unwrap(), expected);
+
+            let mut trickle = Trickle {
+                data: &data,
+                reads: 0,
+            };
+            assert_eq!(digest(&mut trickle, *algorithm).unwrap(), expected);
+        }
+
+        assert_eq!(
+            digest(&mut &data[..], Algorithm::Sha256).unwrap(),
+            "0ED3EA439A09C5D23A79DEEB804194472F496372DE097B0929AFF11FA8A042DC"
+        );
+    }
+
+    #[test]
+    fn parses_algorithm_names() {
+        assert_eq!(Algorithm::parse("MD5"), Some(Algorithm::Md5));
+        assert_eq!(Algorithm::parse("sha-256"), Some(Algorithm::Sha256));
+        assert_eq!(Algorithm::parse("SHA512"), Some(Algorithm::Sha512));
+        assert_eq!(Algorithm::parse("sha1"), None);
+
+        for algorithm in &[Algorithm::Md5, Algorithm::Sha256, Algorithm::Sha512] {
+            assert_eq!(Algorithm::parse(algorithm.name()), Some(*algorithm));
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use crate::config::Config;
+
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// connections refused by the filter since startup
+static REJECTED: AtomicU64 = AtomicU64::new(0);
+
+/// An address range in CIDR notation, e.g. `10.0.0.0/8` or `fd00::/8`. A plain address
+/// matches only itself.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u32,
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Cidr, String> {
+        let mut parts = value.splitn(2, '/');
+        let network: IpAddr = parts
+            .next()
+            .unwrap()
+            .parse()
+            .map_err(|_| format!("invalid address in {}", value))?;
+
+        let max_len = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix_len = match parts.next() {
+            Some(len) => len
+                .parse()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| format!("invalid prefix length in {}", value))?,
+            None => max_len,
+        };
+
+        // a mapped range is matched like the IPv4 range it stands for
+        if let IpAddr::V6(v6) = network {
+            if let Some(v4) = v6.to_ipv4_mapped().filter(|_| prefix_len >= 96) {
+                return Ok(Cidr {
+                    network: IpAddr::V4(v4),
+                    prefix_len: prefix_len - 96,
+                });
+            }
+        }
+
+        Ok(Cidr {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients on a dual-stack listener show up as IPv4-mapped IPv6 addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Decides which client addresses may connect, from the `allow` and `deny` lists of the
+/// configuration. A denied address is refused even if it is also allowed, and as soon as
+/// `allow` has an entry every address it doesn't cover is refused.
+pub struct IpFilter;
+
+impl IpFilter {
+    /// Whether a client at `ip` may connect, counts the refused ones
+    /// # Examples
+    /// ```
+    /// if !IpFilter::allows(peer.ip()) {
+    ///     warn!(log, "Refused connection from {}", peer);
+    /// }
+    /// ```
+    pub fn allows(ip: IpAddr) -> bool {
+        let config = Config::get();
+        let allowed = IpFilter::permits(&config.allow, &config.deny, ip);
+
+        if !allowed {
+            REJECTED.fetch_add(1, Ordering::Relaxed);
+        }
+        allowed
+    }
+
+    fn permits(allow: &[Cidr], deny: &[Cidr], ip: IpAddr) -> bool {
+        !deny.iter().any(|cidr| cidr.contains(ip))
+            && (allow.is_empty() || allow.iter().any(|cidr| cidr.contains(ip)))
+    }
+
+    /// Connections refused since startup
+    pub fn rejected() -> u64 {
+        REJECTED.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(value: &str) -> Cidr {
+        Cidr::try_from(value.to_string()).unwrap()
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_ranges_and_plain_addresses() {
+        assert_eq!(cidr("10.0.0.0/8").to_string(), "10.0.0.0/8");
+        assert_eq!(cidr("192.168.1.7").to_string(), "192.168.1.7/32");
+        assert_eq!(cidr("fd00::/8").to_string(), "fd00::/8");
+        assert_eq!(cidr("::1").to_string(), "::1/128");
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        for value in &[
+            "",
+            "10.0.0/8",
+            "10.0.0.0/",
+            "10.0.0.0/33",
+            "10.0.0.0/-1",
+            "10.0.0.0/8/8",
+            "fd00::/129",
+            "example.com/8",
+        ] {
+            assert!(Cidr::try_from(value.to_string()).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn matches_within_the_prefix() {
+        let range = cidr("10.1.0.0/16");
+        assert!(range.contains(ip("10.1.0.0")));
+        assert!(range.contains(ip("10.1.255.255")));
+        assert!(!range.contains(ip("10.2.0.0")));
+
+        let range = cidr("fd00:1::/32");
+        assert!(range.contains(ip("fd00:1::42")));
+        assert!(!range.contains(ip("fd00:2::42")));
+    }
+
+    #[test]
+    fn prefix_length_zero_matches_every_address_of_its_family() {
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+        assert!(!cidr("::/0").contains(ip("203.0.113.9")));
+    }
+
+    #[test]
+    fn full_prefix_length_matches_a_single_address() {
+        assert!(cidr("203.0.113.9/32").contains(ip("203.0.113.9")));
+        assert!(!cidr("203.0.113.9/32").contains(ip("203.0.113.8")));
+        assert!(cidr("2001:db8::1/128").contains(ip("2001:db8::1")));
+        assert!(!cidr("2001:db8::1/128").contains(ip("2001:db8::2")));
+    }
+
+    #[test]
+    fn mapped_addresses_match_ipv4_ranges() {
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("::ffff:11.1.2.3")));
+
+        assert_eq!(cidr("::ffff:10.0.0.0/104").to_string(), "10.0.0.0/8");
+        assert!(cidr("::ffff:10.0.0.0/104").contains(ip("10.1.2.3")));
+        assert!(cidr("::ffff:10.0.0.0/104").contains(ip("::ffff:10.1.2.3")));
+    }
+
+    #[test]
+    fn deny_overrides_allow() {
+        let allow = [cidr("10.0.0.0/8")];
+        let deny = [cidr("10.6.6.0/24")];
+
+        assert!(IpFilter::permits(&allow, &deny, ip("10.1.2.3")));
+        assert!(!IpFilter::permits(&allow, &deny, ip("10.6.6.6")));
+        assert!(!IpFilter::permits(&allow, &deny, ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn empty_allow_list_allows_everyone_not_denied() {
+        let deny = [cidr("192.0.2.0/24")];
+
+        assert!(IpFilter::permits(&[], &[], ip("198.51.100.1")));
+        assert!(IpFilter::permits(&[], &deny, ip("198.51.100.1")));
+        assert!(!IpFilter::permits(&[], &deny, ip("192.0.2.1")));
+        assert!(!IpFilter::permits(&[], &deny, ip("::ffff:192.0.2.1")));
+    }
+}
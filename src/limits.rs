@@ -0,0 +1,274 @@
+use crate::auth::MAX_LOGIN_ATTEMPTS;
+use crate::config::Config;
+
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    // open connections per client address
+    static ref CONNECTIONS: Mutex<HashMap<IpAddr, usize>> = Mutex::new(HashMap::new());
+    // request token buckets per client address
+    static ref BUCKETS: Mutex<HashMap<IpAddr, Bucket>> = Mutex::new(HashMap::new());
+    // failed LOGIN attempts in a row per client address
+    static ref FAILED_LOGINS: Mutex<HashMap<IpAddr, FailedLogins>> = Mutex::new(HashMap::new());
+}
+
+// buckets and failed logins are pruned once there are more than this many
+const MAX_BUCKETS: usize = 4096;
+
+/// Seconds an address can't log in after `MAX_LOGIN_ATTEMPTS` failed attempts in a row
+pub const LOGIN_LOCKOUT_SECS: u64 = 300;
+
+#[derive(Debug)]
+struct FailedLogins {
+    count: u32,
+    last: Instant,
+}
+
+impl FailedLogins {
+    // failures are forgotten once an address has been quiet for the lockout period
+    fn expired(&self) -> bool {
+        self.last.elapsed() >= Duration::from_secs(LOGIN_LOCKOUT_SECS)
+    }
+}
+
+/// Token bucket refilled at `requests_per_sec`, holding up to one second worth of requests
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: f64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate.max(1.0));
+        self.updated = now;
+    }
+}
+
+/// A connection counted against the per-address limit, released when dropped
+#[derive(Debug)]
+pub struct ConnectionSlot {
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut connections = match CONNECTIONS.lock() {
+            Ok(connections) => connections,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        if let Some(count) = connections.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Per client address limits, configured in the `[limits]` section
+pub struct Limits;
+
+impl Limits {
+    /// Count a new connection from `ip`, `None` when the address already has
+    /// `max_connections_per_ip` connections open
+    /// # Examples
+    /// ```
+    /// match Limits::connect(peer.ip()) {
+    ///     Some(slot) => session.connection = Some(slot),
+    ///     None => session.reject(Status::TooManyRequests, "too many connections"),
+    /// }
+    /// ```
+    pub fn connect(ip: IpAddr) -> Option<ConnectionSlot> {
+        let mut connections = CONNECTIONS.lock().unwrap();
+        let count = connections.get(&ip).copied().unwrap_or(0);
+
+        if let Some(max) = Config::get().limits.max_connections_per_ip {
+            if count >= max {
+                return None;
+            }
+        }
+
+        connections.insert(ip, count + 1);
+        Some(ConnectionSlot { ip })
+    }
+
+    /// Take a request from the bucket of `ip`, false when it is sending more than
+    /// `requests_per_sec`
+    pub fn allow_request(ip: IpAddr) -> bool {
+        let rate = match Config::get().limits.requests_per_sec {
+            Some(rate) => rate,
+            None => return true,
+        };
+
+        let mut buckets = BUCKETS.lock().unwrap();
+
+        if buckets.len() > MAX_BUCKETS {
+            // a full bucket is no different from a new one
+            for bucket in buckets.values_mut() {
+                bucket.refill(rate);
+            }
+            buckets.retain(|_, bucket| bucket.tokens < rate.max(1.0));
+        }
+
+        let bucket = buckets.entry(ip).or_insert_with(|| Bucket {
+            tokens: rate.max(1.0),
+            updated: Instant::now(),
+        });
+        bucket.refill(rate);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Whether `ip` failed to log in `MAX_LOGIN_ATTEMPTS` times in a row recently, on any of
+    /// its connections
+    pub fn login_locked(ip: IpAddr) -> bool {
+        match FAILED_LOGINS.lock().unwrap().get(&ip) {
+            Some(failed) => failed.count >= MAX_LOGIN_ATTEMPTS && !failed.expired(),
+            None => false,
+        }
+    }
+
+    /// Count a failed LOGIN from `ip`, returns the number of failures in a row
+    pub fn login_failed(ip: IpAddr) -> u32 {
+        let mut failed_logins = FAILED_LOGINS.lock().unwrap();
+
+        if failed_logins.len() > MAX_BUCKETS {
+            failed_logins.retain(|_, failed| !failed.expired());
+        }
+
+        let failed = failed_logins.entry(ip).or_insert(FailedLogins {
+            count: 0,
+            last: Instant::now(),
+        });
+        if failed.expired() {
+            failed.count = 0;
+        }
+        failed.count += 1;
+        failed.last = Instant::now();
+        failed.count
+    }
+
+    /// Forget the failed LOGIN attempts of `ip` after it logged in
+    pub fn login_succeeded(ip: IpAddr) {
+        FAILED_LOGINS.lock().unwrap().remove(&ip);
+    }
+}
+
+/// Keeps a single transfer at or below a rate by sleeping between chunks
+pub struct Throttle {
+    bytes_per_sec: Option<u64>,
+    start: Instant,
+    transferred: u64,
+}
+
+impl Throttle {
+    /// Throttle to `bytes_per_sec`, `None` doesn't throttle at all
+    /// # Examples
+    /// ```
+    /// let mut throttle = Throttle::new(Config::get().limits.get_bytes_per_sec);
+    /// ```
+    pub fn new(bytes_per_sec: Option<u64>) -> Throttle {
+        Throttle {
+            bytes_per_sec,
+            start: Instant::now(),
+            transferred: 0,
+        }
+    }
+
+    /// Account for `size` bytes about to be or just transferred, sleeps until the transfer is
+    /// back on schedule
+    pub fn consume(&mut self, size: usize) {
+        let bytes_per_sec = match self.bytes_per_sec {
+            Some(bytes_per_sec) if bytes_per_sec > 0 => bytes_per_sec,
+            _ => return,
+        };
+
+        self.transferred += size as u64;
+        let due = Duration::from_secs_f64(self.transferred as f64 / bytes_per_sec as f64);
+        let elapsed = self.start.elapsed();
+
+        if due > elapsed {
+            thread::sleep(due - elapsed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bucket(tokens: f64, age: Duration) -> Bucket {
+        Bucket {
+            tokens,
+            updated: Instant::now() - age,
+        }
+    }
+
+    #[test]
+    fn bucket_refills_at_the_rate() {
+        let mut bucket = bucket(0.0, Duration::from_millis(500));
+        bucket.refill(4.0);
+
+        assert!(
+            bucket.tokens >= 2.0 && bucket.tokens < 2.5,
+            "{}",
+            bucket.tokens
+        );
+    }
+
+    #[test]
+    fn bucket_holds_one_second_of_requests() {
+        let mut full = bucket(0.0, Duration::from_secs(60));
+        full.refill(10.0);
+        assert_eq!(full.tokens, 10.0);
+
+        let mut topped_up = bucket(9.5, Duration::from_secs(1));
+        topped_up.refill(10.0);
+        assert_eq!(topped_up.tokens, 10.0);
+    }
+
+    #[test]
+    fn slow_bucket_holds_a_single_request() {
+        let mut bucket = bucket(0.0, Duration::from_secs(60));
+        bucket.refill(0.1);
+
+        assert_eq!(bucket.tokens, 1.0);
+    }
+
+    #[test]
+    fn unlimited_throttle_doesnt_wait() {
+        let started = Instant::now();
+
+        Throttle::new(None).consume(1 << 30);
+        Throttle::new(Some(0)).consume(1 << 30);
+
+        assert!(started.elapsed() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn throttle_keeps_to_the_rate() {
+        let started = Instant::now();
+
+        let mut throttle = Throttle::new(Some(1000));
+        throttle.consume(100);
+        throttle.consume(100);
+
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(200), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(500), "{:?}", elapsed);
+    }
+}
@@ -1,292 +1,324 @@
 extern crate slog;
 extern crate slog_term;
 
+use chrono::TimeZone;
 use slog::*;
 
-use std::borrow::Cow;
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
-use std::net::{Shutdown, TcpListener, TcpStream};
-use std::str;
+use std::env;
+use std::io;
+use std::net::TcpListener;
+use std::process;
 use std::sync::Mutex;
-use std::thread;
 
+mod acl;
+mod auth;
+mod blobs;
 mod command;
+mod compression;
+mod config;
 mod file_manager;
-
+mod hash;
+mod ip_filter;
+mod limits;
+mod pool;
+mod quota;
+mod request;
+mod response;
+mod scrubber;
+mod server;
+mod session;
+mod tls;
+mod trash;
+mod versions;
+mod watcher;
+
+use crate::acl::Acl;
+use crate::auth::{Claims, Token, UserStore};
+use crate::blobs::Blobs;
+use crate::config::{Config, CONFIG_FILE};
 use crate::file_manager::FileManager;
+use crate::ip_filter::{Cidr, IpFilter};
+use crate::pool::ThreadPool;
+use crate::quota::{Kind, Quota};
+use crate::scrubber::Scrubber;
+use crate::server::Server;
+use crate::trash::Trash;
+use crate::versions::Versions;
+use crate::watcher::Watcher;
+
+const USAGE: &str = "usage: socket-server [adduser <username>
+                     | grant <user|@group|*> <prefix> <rwdl>
+                     | revoke <user|@group|*> <prefix>
+                     | join <username> <group>
+                     | token <username> <rwdl> <prefix[,prefix...]> <ttl-seconds>
+                     | quota <user|prefix> <name> <max-bytes|-> <max-files|->
+                     | corrupted]";
+
+/// `socket-server adduser <username>`, reads the password from the first line of stdin
+fn add_user(args: &[String], log: Logger) {
+    let username = match args {
+        [username] if !username.starts_with('@') && username != "*" => username,
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
 
-fn handle_message(stream: TcpStream, msg: Cow<str>, _log: Logger) {
-    let log = _log.clone();
-    let mut pattern = msg.split(' ').fuse();
-    let method = pattern.next();
-    let file_name = pattern.next();
-    let mut hash = pattern.next();
-
-    if hash.unwrap().is_empty() {
-        hash = Some(hash.unwrap());
-    }
-
-    info!(_log, "Method          : {:?}", method.unwrap());
-    info!(_log, "Filename        : {:?}", file_name.unwrap());
-    info!(_log, "Hash            : {:?}", hash.unwrap());
-
-    let mut valid_method = true;
+    let mut password = String::new();
+    io::stdin().read_line(&mut password).unwrap();
+    let password = password.trim_end_matches(&['\r', '\n'][..]);
 
-    match method {
-        Some("GET") => info!(log, "GET method"),
-        Some("LIST") => info!(log, "LIST method"),
-        Some("POST") => info!(log, "POST method"),
-        Some("DELETE") => info!(log, "DELETE method"),
-        Some("PUT") => info!(log, "PUT method"),
-        _ => valid_method = false,
+    if password.is_empty() {
+        crit!(log, "Password may not be empty");
+        process::exit(1);
     }
 
-    if valid_method {
-        let command = command::Command::new(method.unwrap(), file_name.unwrap(), hash.unwrap());
-        command.execute_method(stream.try_clone().unwrap(), log.clone());
-    }
+    UserStore::initialize(log.clone());
+    UserStore::set_password(username, password).unwrap();
+    info!(log, "Saved user {}", username);
 }
 
-fn handle_client(mut stream: TcpStream, _log: Logger) {
-    let mut data = [0 as u8; 4096];
-    let log = _log.clone();
-    let mut remaining_data: usize = 0;
-    let mut file_name_t: String = "".parse().unwrap();
-
-    'stream_reader: while match stream.read(&mut data) {
-        Ok(size) => {
-            if size == 0 {
-                stream.shutdown(Shutdown::Both).unwrap();
-                break 'stream_reader;
-            }
-            let msg = String::from_utf8_lossy(&data[0..size]);
-            let msg_parse: String = msg.parse().unwrap();
-
-            let mut pattern = msg_parse.split_whitespace().fuse();
-            let method = pattern.next();
-
-            if method.is_none() {
-                break 'stream_reader;
-            }
+/// `socket-server grant|revoke|join ...`, manages the access control lists
+fn manage_acl(command: &str, args: &[String], log: Logger) {
+    Acl::initialize(log.clone());
 
-            if remaining_data == 0 && !file_name_t.is_empty() {
-                info!(_log, "File writing is done for: {:?}", file_name_t);
-                let response = "AFTP/1.0 OK\n".to_string();
-                stream.write_all(response.as_bytes()).unwrap();
+    match (command, args) {
+        ("grant", [principal, prefix, permissions])
+            if permissions.chars().all(|c| "rwdl".contains(c)) =>
+        {
+            Acl::grant(principal, prefix, permissions).unwrap();
+            info!(
+                log,
+                "Granted {} on {:?} to {}", permissions, prefix, principal
+            );
+        }
+        ("revoke", [principal, prefix]) => {
+            if Acl::revoke(principal, prefix).unwrap() {
+                info!(log, "Revoked {:?} from {}", prefix, principal);
+            } else {
+                warn!(log, "No rule for {:?} and {}", prefix, principal);
             }
-
-            if remaining_data != 0 {
-                if remaining_data > 0 {
-                    remaining_data -= size;
-                    let file_path_t = &("../files/".to_string() + file_name_t.as_ref());
-                    let file = OpenOptions::new().append(true).open(file_path_t);
-                    file.unwrap().write_all(&data[0..size]).unwrap();
-
-                    let mut _file = File::open(file_path_t).unwrap();
-
-                    info!(_log, "Remaining data: {:?}", remaining_data);
-                } else {
-                    info!(_log, "Streaming done");
-                }
+        }
+        ("join", [username, group]) => {
+            UserStore::initialize(log.clone());
+            if Acl::join(username, group).unwrap() {
+                info!(log, "Added {} to group {}", username, group);
+            } else {
+                crit!(log, "No user {}, add it with adduser first", username);
+                process::exit(1);
             }
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    }
+}
 
-            if method.unwrap() == "LOCK" {
-                let file_name = pattern.next();
-                let file = FileManager::get()
-                    .lock()
-                    .unwrap()
-                    .as_mut()
-                    .unwrap()
-                    .lock_file(file_name.unwrap(), true);
-
-                if file {
-                    let response = "AFTP/1.0 LOCKED\n".to_string();
-                    stream.write_all(response.as_bytes()).unwrap();
-                } else {
-                    let response = "AFTP/1.0 NOT FOUND\n".to_string();
-                    stream.write_all(response.as_bytes()).unwrap();
+/// `socket-server token ...`, prints a bearer token signed with the configured key
+fn mint_token(args: &[String], log: Logger) {
+    let (user, permissions, paths, ttl) = match args {
+        [user, permissions, paths, ttl] if permissions.chars().all(|c| "rwdl".contains(c)) => {
+            match ttl.parse::<i64>() {
+                Ok(ttl) if ttl > 0 => (user, permissions, paths, ttl),
+                _ => {
+                    eprintln!("{}", USAGE);
+                    process::exit(2);
                 }
             }
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    Config::initialize(CONFIG_FILE, log.clone());
+    let key_file = match &Config::get().token_key_file {
+        Some(key_file) => key_file.clone(),
+        None => {
+            crit!(log, "token_key_file is not configured");
+            process::exit(1);
+        }
+    };
+
+    let claims = Claims {
+        user: user.clone(),
+        permissions: permissions.clone(),
+        paths: paths.split(',').map(String::from).collect(),
+        expires: chrono::Utc::now().timestamp() + ttl,
+    };
+
+    let token = match Token::mint(&key_file, &claims) {
+        Ok(token) => token,
+        Err(e) => {
+            crit!(log, "Failed to mint token: {}", e);
+            process::exit(1);
+        }
+    };
+    info!(
+        log,
+        "Minted token for {} with {} on {:?}, valid for {}s",
+        claims.user,
+        claims.permissions,
+        claims.paths,
+        ttl
+    );
+    println!("{}", token);
+}
 
-            if method.unwrap() == "UNLOCK" {
-                let file_name = pattern.next();
-                let file = FileManager::get()
-                    .lock()
-                    .unwrap()
-                    .as_mut()
-                    .unwrap()
-                    .lock_file(file_name.unwrap(), false);
-
-                if file {
-                    let response = "AFTP/1.0 UNLOCKED\n".to_string();
-                    stream.write_all(response.as_bytes()).unwrap();
-                } else {
-                    let response = "AFTP/1.0 NOT FOUND\n".to_string();
-                    stream.write_all(response.as_bytes()).unwrap();
+/// `socket-server quota ...`, sets or with `- -` removes the quota of a user or prefix
+fn set_quota(args: &[String], log: Logger) {
+    let limit = |value: &str| match value {
+        "-" => Some(None),
+        value => value.parse::<u64>().ok().map(Some),
+    };
+
+    let (kind, subject, max_bytes, max_files) = match args {
+        [kind, subject, max_bytes, max_files] => {
+            match (Kind::parse(kind), limit(max_bytes), limit(max_files)) {
+                (Some(kind), Some(max_bytes), Some(max_files)) => {
+                    (kind, subject, max_bytes, max_files)
                 }
-            }
-
-            if method.unwrap() == "PUT" {
-                pattern.next();
-                let protocol = pattern.next();
-                pattern.next();
-                let file_name = pattern.next();
-                pattern.next();
-                let remaining_data_t = pattern.next().unwrap();
-                remaining_data = remaining_data_t.parse::<usize>().unwrap();
-                file_name_t = file_name.unwrap().to_string();
-
-                info!(_log, "Remaining data message: {:?}", remaining_data);
-                info!(_log, "Filename message: {:?}", file_name);
-                info!(_log, "Protocol message: {:?}", protocol);
-
-                if protocol.unwrap() == "AFTP/1.0" {
-                    pattern.next().unwrap();
-                    let hash = pattern.next().unwrap();
-                    info!(_log, "hash: {:?}", hash);
-
-                    let files = FileManager::get()
-                        .lock()
-                        .unwrap()
-                        .as_mut()
-                        .unwrap()
-                        .list()
-                        .to_vec();
-
-                    for _file in &files {
-                        if _file.filename == file_name.unwrap() {
-                            if _file.locked {
-                                let s = "AFTP/1.0 423 Locked\n".to_string();
-                                stream.write_all(s.as_bytes()).unwrap();
-                            } else {
-                                FileManager::get()
-                                    .lock()
-                                    .unwrap()
-                                    .as_mut()
-                                    .unwrap()
-                                    .lock_file(&_file.filename, true);
-                            }
-                        }
-                    }
-
-                    let mut vector = Vec::new();
-                    let mut char_byte = 0;
-
-                    for d in msg.split('\n') {
-                        if char_byte == 1 {
-                            vector.push(d);
-                        }
-                        if d.contains(hash) {
-                            char_byte += 1;
-                        }
-                        continue;
-                    }
-
-                    let file_path =
-                        &("../files/".to_string() + file_name.unwrap().to_string().as_ref());
-
-                    let mut file = OpenOptions::new()
-                        .create(true)
-                        .truncate(true)
-                        .open(file_path)
-                        .unwrap();
-
-                    for i in vector {
-                        file.write_all(i.as_bytes()).unwrap();
-                    }
-
-                    remaining_data -= size;
-
-                    FileManager::get().lock().unwrap().as_mut().unwrap().create(
-                        file,
-                        file_name.unwrap().to_string(),
-                        file_path.to_string(),
-                        hash.to_string(),
-                    );
-
-                    FileManager::get()
-                        .lock()
-                        .unwrap()
-                        .as_mut()
-                        .unwrap()
-                        .lock_file(&String::from(file_name.unwrap()), false);
-
-                    let response = "AFTP/1.0 OK\n".to_string();
-                    stream.write_all(response.as_bytes()).unwrap();
-
-                    info!(_log, "Stream is done");
+                _ => {
+                    eprintln!("{}", USAGE);
+                    process::exit(2);
                 }
             }
-
-            if msg.ends_with("AFTP/1.0\n") {
-                info!(_log, "Protocol message : {:?}", msg);
-                info!(_log, "Protocol seems correct, proceeding");
-                handle_message(stream.try_clone().unwrap(), msg, _log.clone());
-            }
-            true
         }
-        Err(_) => {
-            error!(
-                log,
-                "An error occurred, terminating connection with {}",
-                stream.peer_addr().unwrap()
-            );
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    Quota::initialize(log.clone());
+    Quota::set(kind, subject, max_bytes, max_files).unwrap();
+    info!(
+        log,
+        "Set quota for {} {:?}: {:?} bytes, {:?} files",
+        kind.name(),
+        subject,
+        max_bytes,
+        max_files
+    );
+}
 
-            FileManager::get()
-                .lock()
-                .unwrap()
-                .as_mut()
-                .unwrap()
-                .unlock_all_files();
+/// `socket-server corrupted`, lists the files the scrubber found corrupted
+fn report_corrupted(args: &[String], log: Logger) {
+    if !args.is_empty() {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
 
-            stream.shutdown(Shutdown::Both).unwrap();
-            false
-        }
-    } {}
+    Scrubber::initialize(log.clone());
+    let corrupted = Scrubber::corrupted().unwrap();
+
+    for corruption in &corrupted {
+        let detected = chrono::Utc
+            .timestamp_opt(corruption.detected, 0)
+            .single()
+            .map_or(corruption.detected.to_string(), |detected| {
+                detected.to_rfc3339()
+            });
+        println!(
+            "{} {} expected {} actual {} detected {}",
+            corruption.filename,
+            corruption.algorithm,
+            corruption.expected,
+            corruption.actual,
+            detected
+        );
+    }
+    info!(log, "{} corrupted files", corrupted.len());
 }
 
 fn main() {
-    let listener = TcpListener::bind("127.0.0.1:9123").unwrap();
-
     let decorator = slog_term::TermDecorator::new().build();
     let drain = slog_term::FullFormat::new(decorator).build().fuse();
     let drain_mutex = Mutex::new(drain);
 
     let log = slog::Logger::root(drain_mutex.fuse(), o!());
 
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("adduser") => return add_user(&args[1..], log),
+        Some(command @ "grant") | Some(command @ "revoke") | Some(command @ "join") => {
+            return manage_acl(command, &args[1..], log)
+        }
+        Some("token") => return mint_token(&args[1..], log),
+        Some("quota") => return set_quota(&args[1..], log),
+        Some("corrupted") => return report_corrupted(&args[1..], log),
+        Some(_) => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+        None => {}
+    }
+
     info!(log, "Starting socket server");
 
+    Config::initialize(CONFIG_FILE, log.clone());
+    let listener = TcpListener::bind(&Config::get().listen).unwrap();
+
     FileManager::initialize(log.clone());
-    FileManager::get()
-        .lock()
-        .unwrap()
-        .as_mut()
-        .unwrap()
-        .get_files(log.clone());
-
-    let log = log.clone();
-
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let log = log.clone();
-                thread::spawn(move || {
-                    info!(log, "New connection: {}", stream.peer_addr().unwrap());
-                    handle_client(stream, log)
-                });
-            }
-            Err(e) => {
-                FileManager::get()
-                    .lock()
-                    .unwrap()
-                    .as_mut()
-                    .unwrap()
-                    .unlock_all_files();
-
-                info!(log, "Error: {}", e);
-            }
-        }
+    UserStore::initialize(log.clone());
+    Token::initialize(log.clone());
+    Acl::initialize(log.clone());
+    Quota::initialize(log.clone());
+    Scrubber::initialize(log.clone());
+    Versions::initialize(log.clone());
+    Trash::initialize(log.clone());
+    Blobs::initialize(log.clone());
+    FileManager::clean_uploads(log.clone());
+    Blobs::collect(log.clone());
+    FileManager::index(log.clone(), Config::get().background_indexing);
+    if Config::get().watch_files {
+        Watcher::spawn(log.clone()).unwrap();
+    }
+    Scrubber::spawn(log.clone());
+    Trash::spawn(log.clone());
+    Versions::spawn(log.clone());
+
+    let config = Config::get();
+    if !config.allow.is_empty() || !config.deny.is_empty() {
+        let list = |cidrs: &[Cidr]| {
+            cidrs
+                .iter()
+                .map(Cidr::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        info!(
+            log,
+            "Accepting connections from [{}], refusing [{}]",
+            list(&config.allow),
+            list(&config.deny)
+        );
     }
-    drop(listener);
+    let pool = ThreadPool::new(config.worker_threads, config.accept_queue, log.clone());
+
+    let tls = config.tls.as_ref().map(|tls_config| {
+        info!(log, "Serving TLS with certificate {}", tls_config.cert_file);
+        tls::server_config(tls_config).unwrap()
+    });
+
+    let mut server = Server::new(listener, pool, tls, log.clone()).unwrap();
+    server.run().unwrap();
+
+    let mut instance = FileManager::get().lock().unwrap();
+    let manager = instance.as_mut().unwrap();
+    manager.unlock_all_files();
+    manager.flush(log.clone());
+    drop(instance);
+
+    FileManager::clean_uploads(log.clone());
+
+    info!(
+        log,
+        "Socket server stopped, refused {} connections by address",
+        IpFilter::rejected()
+    );
+    // workers still busy past the deadline are not waited for
+    process::exit(0);
 }
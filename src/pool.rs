@@ -0,0 +1,103 @@
+use slog::Logger;
+use slog::*;
+
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Returned when every worker is busy and the queue is full
+#[derive(Debug)]
+pub struct Busy;
+
+struct Worker {
+    id: usize,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Worker {
+    fn new(id: usize, receiver: Arc<Mutex<Receiver<Job>>>, log: Logger) -> Worker {
+        let thread = thread::Builder::new()
+            .name(format!("worker-{}", id))
+            .spawn(move || loop {
+                let job = receiver.lock().unwrap().recv();
+
+                match job {
+                    Ok(job) => {
+                        // a panicking job must not take the worker down with it
+                        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                            error!(log, "Job panicked on worker {}", id);
+                        }
+                    }
+                    // the pool has been dropped
+                    Err(_) => break,
+                }
+            })
+            .unwrap();
+
+        Worker {
+            id,
+            thread: Some(thread),
+        }
+    }
+}
+
+/// Fixed size pool of worker threads fed by a bounded queue
+pub struct ThreadPool {
+    workers: Vec<Worker>,
+    sender: Option<SyncSender<Job>>,
+    log: Logger,
+}
+
+impl ThreadPool {
+    /// Create a pool with `size` workers and room for `queue_depth` waiting jobs
+    /// # Examples
+    /// ```
+    /// let pool = ThreadPool::new(config.worker_threads, config.accept_queue, log.clone());
+    /// ```
+    pub fn new(size: usize, queue_depth: usize, log: Logger) -> ThreadPool {
+        assert!(size > 0, "thread pool needs at least one worker");
+
+        let (sender, receiver) = sync_channel(queue_depth);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..size)
+            .map(|id| Worker::new(id, receiver.clone(), log.clone()))
+            .collect();
+
+        ThreadPool {
+            workers,
+            sender: Some(sender),
+            log,
+        }
+    }
+
+    /// Queue a job, fails without blocking when the pool is saturated
+    pub fn execute<F>(&self, job: F) -> std::result::Result<(), Busy>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match self.sender.as_ref().unwrap().try_send(Box::new(job)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(Busy),
+            Err(TrySendError::Disconnected(_)) => Err(Busy),
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    /// Waits for queued and running jobs to finish
+    fn drop(&mut self) {
+        drop(self.sender.take());
+
+        for worker in &mut self.workers {
+            if let Some(thread) = worker.thread.take() {
+                debug!(self.log, "Shutting down worker {}", worker.id);
+                thread.join().unwrap();
+            }
+        }
+    }
+}
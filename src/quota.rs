@@ -0,0 +1,589 @@
+use crate::config::Config;
+use crate::file_manager::{FileManager, TFile, DATABASE};
+
+use lazy_static::lazy_static;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use slog::Logger;
+use slog::*;
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+lazy_static! {
+    // uploads that passed the quota check but aren't in the index yet
+    static ref RESERVED: Mutex<Vec<Reserved>> = Mutex::new(Vec::new());
+    // held from checking an upload to reserving its space
+    static ref CHECKING: Mutex<()> = Mutex::new(());
+}
+
+static RESERVATION_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug)]
+struct Reserved {
+    id: usize,
+    user: Option<String>,
+    file_name: String,
+    size: u64,
+}
+
+/// What a quota applies to
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    /// Files uploaded by a user
+    User,
+    /// Files whose name starts with a prefix
+    Prefix,
+}
+
+impl Kind {
+    pub fn parse(value: &str) -> Option<Kind> {
+        match value {
+            "user" => Some(Kind::User),
+            "prefix" => Some(Kind::Prefix),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::User => "user",
+            Kind::Prefix => "prefix",
+        }
+    }
+}
+
+/// Current usage of a user or prefix and its limits, `None` is unlimited
+#[derive(Debug, Serialize)]
+pub struct Usage {
+    pub kind: Kind,
+    pub subject: String,
+    pub bytes: u64,
+    pub files: u64,
+    pub max_bytes: Option<u64>,
+    pub max_files: Option<u64>,
+}
+
+impl Usage {
+    /// Why adding `size` bytes in `files` new files would exceed the limits, if it would
+    fn exceeded_by(&self, size: u64, files: u64) -> Option<String> {
+        if let Some(max_bytes) = self.max_bytes {
+            if self.bytes + size > max_bytes {
+                return Some(format!(
+                    "quota exceeded for {} {}: {} of {} bytes used, upload needs {}",
+                    self.kind.name(),
+                    self.subject,
+                    self.bytes,
+                    max_bytes,
+                    size
+                ));
+            }
+        }
+
+        if let Some(max_files) = self.max_files {
+            if self.files + files > max_files {
+                return Some(format!(
+                    "quota exceeded for {} {}: {} of {} files used",
+                    self.kind.name(),
+                    self.subject,
+                    self.files,
+                    max_files
+                ));
+            }
+        }
+
+        None
+    }
+}
+
+/// An upload that stopped fitting its quotas while it was received
+#[derive(Debug)]
+pub struct Exceeded(pub String);
+
+impl fmt::Display for Exceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Exceeded {}
+
+/// Space held for an upload in progress, released when dropped
+pub struct Reservation {
+    id: usize,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        // also runs while unwinding from a panicked request
+        let mut reserved = match RESERVED.lock() {
+            Ok(reserved) => reserved,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        reserved.retain(|r| r.id != self.id);
+    }
+}
+
+/// Storage quotas on bytes and file count, stored in the `quota` table next to the file index.
+///
+/// A quota applies to the files uploaded by a user, tracked in the `file_owner` table, or to
+/// every file whose name starts with a prefix. Uploads in progress count against the quotas
+/// from the moment they are accepted, earlier versions of files count against the byte limits.
+pub struct Quota;
+
+impl Quota {
+    /// Create the quota tables, should be called once at startup
+    /// # Examples
+    /// ```
+    /// Quota::initialize(log.clone());
+    /// ```
+    pub fn initialize(log: Logger) {
+        info!(log, "Initializing storage quotas");
+        let conn = Connection::open(DATABASE).unwrap();
+        Quota::create_tables(&conn).unwrap();
+    }
+
+    fn create_tables(conn: &Connection) -> rusqlite::Result<()> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS quota (
+                  kind            TEXT NOT NULL,
+                  subject         TEXT NOT NULL,
+                  max_bytes       INTEGER,
+                  max_files       INTEGER,
+                  PRIMARY KEY (kind, subject)
+                  )",
+            params![],
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS file_owner (
+                  filename        TEXT PRIMARY KEY,
+                  owner           TEXT NOT NULL
+                  )",
+            params![],
+        )?;
+
+        Ok(())
+    }
+
+    /// Set the limits for a user or prefix, removes the quota when both are unlimited
+    pub fn set(
+        kind: Kind,
+        subject: &str,
+        max_bytes: Option<u64>,
+        max_files: Option<u64>,
+    ) -> rusqlite::Result<()> {
+        let conn = Connection::open(DATABASE)?;
+
+        if max_bytes.is_none() && max_files.is_none() {
+            conn.execute(
+                "DELETE FROM quota WHERE kind = ?1 AND subject = ?2",
+                params![kind.name(), subject],
+            )?;
+        } else {
+            conn.execute(
+                "INSERT INTO quota (kind, subject, max_bytes, max_files) VALUES (?1, ?2, ?3, ?4)
+                      ON CONFLICT(kind, subject) DO UPDATE SET max_bytes = ?3, max_files = ?4",
+                params![
+                    kind.name(),
+                    subject,
+                    max_bytes.map(|n| n as i64),
+                    max_files.map(|n| n as i64)
+                ],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Record who uploaded a file, `None` for an anonymous client or a deleted file
+    pub fn set_owner(file_name: &str, owner: Option<&str>) -> rusqlite::Result<()> {
+        let conn = Connection::open(DATABASE)?;
+
+        match owner {
+            Some(owner) => conn.execute(
+                "INSERT INTO file_owner (filename, owner) VALUES (?1, ?2)
+                      ON CONFLICT(filename) DO UPDATE SET owner = ?2",
+                params![file_name, owner],
+            )?,
+            None => conn.execute(
+                "DELETE FROM file_owner WHERE filename = ?1",
+                params![file_name],
+            )?,
+        };
+
+        Ok(())
+    }
+
+    /// Who uploaded a file, `None` if it was an anonymous client or isn't known
+    pub fn owner(file_name: &str) -> Option<String> {
+        let conn = Connection::open(DATABASE).ok()?;
+        conn.query_row(
+            "SELECT owner FROM file_owner WHERE filename = ?1",
+            params![file_name],
+            |row| row.get(0),
+        )
+        .optional()
+        .unwrap_or(None)
+    }
+
+    fn limits(conn: &Connection, kind: Kind, subject: &str) -> (Option<u64>, Option<u64>) {
+        conn.query_row(
+            "SELECT max_bytes, max_files FROM quota WHERE kind = ?1 AND subject = ?2",
+            params![kind.name(), subject],
+            |row| {
+                let max_bytes: Option<i64> = row.get(0)?;
+                let max_files: Option<i64> = row.get(1)?;
+                Ok((max_bytes.map(|n| n as u64), max_files.map(|n| n as u64)))
+            },
+        )
+        .optional()
+        .unwrap_or(None)
+        .unwrap_or((None, None))
+    }
+
+    /// Usage of a user or prefix, leaving out the file `except` and uploads to it, which an
+    /// upload is about to replace. With versioning on, the replaced content stays as a version
+    /// and keeps counting against the byte limits.
+    fn usage_except(conn: &Connection, kind: Kind, subject: &str, except: &str) -> Usage {
+        let versioning = Config::get().keep_versions > 0;
+        let files = FileManager::get().lock().unwrap().as_ref().unwrap().list();
+        Quota::tally(conn, kind, subject, except, &files, versioning)
+    }
+
+    /// Count the indexed `files`, versions and reservations against a user or prefix
+    fn tally(
+        conn: &Connection,
+        kind: Kind,
+        subject: &str,
+        except: &str,
+        files: &[TFile],
+        versioning: bool,
+    ) -> Usage {
+        let (max_bytes, max_files) = Quota::limits(conn, kind, subject);
+
+        let owned: HashSet<String> = match kind {
+            Kind::User => {
+                let mut statement = conn
+                    .prepare("SELECT filename FROM file_owner WHERE owner = ?1")
+                    .unwrap();
+                let owned = statement
+                    .query_map(params![subject], |row| row.get(0))
+                    .unwrap()
+                    .filter_map(|name| name.ok())
+                    .collect();
+                owned
+            }
+            Kind::Prefix => HashSet::new(),
+        };
+
+        let counts = |file_name: &str, user: Option<&str>| match kind {
+            Kind::User => user == Some(subject),
+            Kind::Prefix => file_name.starts_with(subject),
+        };
+
+        let mut usage = Usage {
+            kind,
+            subject: subject.to_string(),
+            bytes: 0,
+            files: 0,
+            max_bytes,
+            max_files,
+        };
+
+        for file in files {
+            let owner = owned.get(&file.filename).map(|_| subject);
+            if !counts(&file.filename, owner) {
+                continue;
+            }
+            if file.filename != except {
+                usage.bytes += file.size;
+                usage.files += 1;
+            } else if versioning {
+                usage.bytes += file.size;
+            }
+        }
+
+        // versions take space but aren't files of their own
+        if let Ok(mut statement) = conn.prepare("SELECT filename, size, uploader FROM version") {
+            let versions = statement
+                .query_map(params![], |row| {
+                    let size: i64 = row.get(1)?;
+                    Ok((row.get::<_, String>(0)?, size as u64, row.get(2)?))
+                })
+                .map(|rows| rows.filter_map(|row| row.ok()).collect())
+                .unwrap_or_else(|_| Vec::new());
+
+            for (file_name, size, uploader) in versions {
+                let uploader: Option<String> = uploader;
+                if counts(&file_name, uploader.as_deref()) {
+                    usage.bytes += size;
+                }
+            }
+        }
+
+        for reserved in RESERVED.lock().unwrap().iter() {
+            if reserved.file_name != except && counts(&reserved.file_name, reserved.user.as_deref())
+            {
+                usage.bytes += reserved.size;
+                usage.files += 1;
+            }
+        }
+
+        usage
+    }
+
+    /// Current usage and limits of a user or prefix
+    /// # Examples
+    /// ```
+    /// let usage = Quota::usage(Kind::User, "alice");
+    /// ```
+    pub fn usage(kind: Kind, subject: &str) -> Usage {
+        let conn = Connection::open(DATABASE).unwrap();
+        Quota::usage_except(&conn, kind, subject, "")
+    }
+
+    /// Check whether an upload of `size` bytes fits every quota that applies to it. The space
+    /// is reserved until the returned reservation is dropped, so concurrent uploads can't
+    /// overshoot a quota together.
+    /// # Examples
+    /// ```
+    /// let reservation = Quota::reserve(Some("alice"), "report.csv", content_length)?;
+    /// ```
+    pub fn reserve(
+        user: Option<&str>,
+        file_name: &str,
+        size: u64,
+    ) -> std::result::Result<Reservation, String> {
+        let _checking = CHECKING.lock().unwrap();
+        Quota::check(user, file_name, size)?;
+
+        let id = RESERVATION_COUNTER.fetch_add(1, Ordering::SeqCst);
+        RESERVED.lock().unwrap().push(Reserved {
+            id,
+            user: user.map(String::from),
+            file_name: file_name.to_string(),
+            size,
+        });
+
+        Ok(Reservation { id })
+    }
+
+    /// Check the quotas again once a reserved upload is received, limits may have been lowered
+    /// or other files uploaded in the meantime
+    pub fn recheck(
+        user: Option<&str>,
+        file_name: &str,
+        size: u64,
+    ) -> std::result::Result<(), String> {
+        let _checking = CHECKING.lock().unwrap();
+        Quota::check(user, file_name, size)
+    }
+
+    fn check(user: Option<&str>, file_name: &str, size: u64) -> std::result::Result<(), String> {
+        let conn = Connection::open(DATABASE).map_err(|e| e.to_string())?;
+
+        let mut subjects: Vec<(Kind, String)> = Vec::new();
+        if let Some(user) = user {
+            subjects.push((Kind::User, user.to_string()));
+        }
+
+        let mut statement = conn
+            .prepare("SELECT subject FROM quota WHERE kind = 'prefix'")
+            .map_err(|e| e.to_string())?;
+        let prefixes = statement
+            .query_map(params![], |row| row.get::<_, String>(0))
+            .map_err(|e| e.to_string())?
+            .filter_map(|prefix| prefix.ok())
+            .filter(|prefix| file_name.starts_with(prefix.as_str()));
+        subjects.extend(prefixes.map(|prefix| (Kind::Prefix, prefix)));
+
+        for (kind, subject) in subjects {
+            let usage = Quota::usage_except(&conn, kind, &subject, file_name);
+            if let Some(message) = usage.exceeded_by(size, 1) {
+                return Err(message);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::Algorithm;
+
+    fn database() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        Quota::create_tables(&conn).unwrap();
+        conn.execute(
+            "CREATE TABLE version (filename TEXT NOT NULL, size INTEGER NOT NULL, uploader TEXT)",
+            params![],
+        )
+        .unwrap();
+        conn
+    }
+
+    fn limit(conn: &Connection, kind: Kind, subject: &str, bytes: Option<i64>, files: Option<i64>) {
+        conn.execute(
+            "INSERT INTO quota (kind, subject, max_bytes, max_files) VALUES (?1, ?2, ?3, ?4)",
+            params![kind.name(), subject, bytes, files],
+        )
+        .unwrap();
+    }
+
+    fn own(conn: &Connection, file_name: &str, owner: &str) {
+        conn.execute(
+            "INSERT INTO file_owner (filename, owner) VALUES (?1, ?2)",
+            params![file_name, owner],
+        )
+        .unwrap();
+    }
+
+    fn version(conn: &Connection, file_name: &str, size: i64, uploader: Option<&str>) {
+        conn.execute(
+            "INSERT INTO version (filename, size, uploader) VALUES (?1, ?2, ?3)",
+            params![file_name, size, uploader],
+        )
+        .unwrap();
+    }
+
+    fn file(filename: &str, size: u64) -> TFile {
+        TFile {
+            filename: filename.to_string(),
+            path: String::new(),
+            hash: String::new(),
+            algorithm: Algorithm::Md5,
+            created: 0,
+            size,
+            modified: 0,
+            locked: false,
+            encoding: None,
+        }
+    }
+
+    fn usage(bytes: u64, files: u64, max_bytes: Option<u64>, max_files: Option<u64>) -> Usage {
+        Usage {
+            kind: Kind::User,
+            subject: "alice".to_string(),
+            bytes,
+            files,
+            max_bytes,
+            max_files,
+        }
+    }
+
+    #[test]
+    fn exceeded_by_byte_limit() {
+        let usage = usage(60, 1, Some(100), None);
+
+        assert_eq!(usage.exceeded_by(40, 1), None);
+        assert_eq!(
+            usage.exceeded_by(41, 1).unwrap(),
+            "quota exceeded for user alice: 60 of 100 bytes used, upload needs 41"
+        );
+    }
+
+    #[test]
+    fn exceeded_by_file_limit() {
+        let usage = usage(0, 2, None, Some(3));
+
+        assert_eq!(usage.exceeded_by(1_000_000, 1), None);
+        assert_eq!(
+            usage.exceeded_by(0, 2).unwrap(),
+            "quota exceeded for user alice: 2 of 3 files used"
+        );
+    }
+
+    #[test]
+    fn unlimited_is_never_exceeded() {
+        assert_eq!(
+            usage(u64::MAX / 2, 1000, None, None).exceeded_by(1 << 40, 1000),
+            None
+        );
+    }
+
+    #[test]
+    fn counts_owned_files_and_prefixes() {
+        let conn = database();
+        limit(&conn, Kind::User, "alice", Some(1000), Some(10));
+        own(&conn, "a.txt", "alice");
+        own(&conn, "b.txt", "alice");
+        own(&conn, "reports-c", "bob");
+        let files = [file("a.txt", 10), file("b.txt", 20), file("reports-c", 40)];
+
+        let alice = Quota::tally(&conn, Kind::User, "alice", "", &files, false);
+        assert_eq!((alice.bytes, alice.files), (30, 2));
+        assert_eq!((alice.max_bytes, alice.max_files), (Some(1000), Some(10)));
+
+        let reports = Quota::tally(&conn, Kind::Prefix, "reports-", "", &files, false);
+        assert_eq!((reports.bytes, reports.files), (40, 1));
+        assert_eq!((reports.max_bytes, reports.max_files), (None, None));
+    }
+
+    #[test]
+    fn versions_count_against_bytes_only() {
+        let conn = database();
+        own(&conn, "a.txt", "alice");
+        version(&conn, "a.txt", 100, Some("alice"));
+        version(&conn, "a.txt", 200, Some("bob"));
+        version(&conn, "reports-q1", 400, None);
+        let files = [file("a.txt", 10)];
+
+        let alice = Quota::tally(&conn, Kind::User, "alice", "", &files, true);
+        assert_eq!((alice.bytes, alice.files), (110, 1));
+
+        let bob = Quota::tally(&conn, Kind::User, "bob", "", &files, true);
+        assert_eq!((bob.bytes, bob.files), (200, 0));
+
+        let reports = Quota::tally(&conn, Kind::Prefix, "reports-", "", &files, true);
+        assert_eq!((reports.bytes, reports.files), (400, 0));
+    }
+
+    #[test]
+    fn replaced_file_is_left_out() {
+        let conn = database();
+        own(&conn, "a.txt", "alice");
+        own(&conn, "b.txt", "alice");
+        let files = [file("a.txt", 10), file("b.txt", 20)];
+
+        let replacing = Quota::tally(&conn, Kind::User, "alice", "a.txt", &files, false);
+        assert_eq!((replacing.bytes, replacing.files), (20, 1));
+
+        // with versioning on the replaced content is kept as a version
+        let versioned = Quota::tally(&conn, Kind::User, "alice", "a.txt", &files, true);
+        assert_eq!((versioned.bytes, versioned.files), (30, 1));
+    }
+
+    #[test]
+    fn reservations_count_unless_replaced() {
+        let conn = database();
+        // reservations are shared by every test, the prefix keeps these apart
+        let reserved = |file_name: &str, size| {
+            let id = RESERVATION_COUNTER.fetch_add(1, Ordering::SeqCst);
+            RESERVED.lock().unwrap().push(Reserved {
+                id,
+                user: None,
+                file_name: file_name.to_string(),
+                size,
+            });
+            Reservation { id }
+        };
+
+        let first = reserved("reserved-a", 100);
+        let _second = reserved("reserved-b", 50);
+
+        let usage = Quota::tally(&conn, Kind::Prefix, "reserved-", "", &[], false);
+        assert_eq!((usage.bytes, usage.files), (150, 2));
+
+        let replacing = Quota::tally(&conn, Kind::Prefix, "reserved-", "reserved-a", &[], false);
+        assert_eq!((replacing.bytes, replacing.files), (50, 1));
+
+        drop(first);
+        let usage = Quota::tally(&conn, Kind::Prefix, "reserved-", "", &[], false);
+        assert_eq!((usage.bytes, usage.files), (50, 1));
+    }
+}
@@ -0,0 +1,323 @@
+use crate::auth::{Claims, Token};
+
+use std::collections::HashMap;
+use std::io;
+use std::io::{BufRead, Read};
+
+pub const PROTOCOL: &str = "AFTP/1.0";
+
+// longest request or header line we accept
+const MAX_LINE_LENGTH: u64 = 8192;
+// most headers a request may carry
+const MAX_HEADERS: usize = 100;
+
+/// A parsed AFTP request.
+///
+/// Every request starts with a single line, `METHOD [arguments] AFTP/1.0`. A PUT request is
+/// followed by `Key: value` headers, a blank line and `Content-Length` bytes of file content.
+///
+/// Arguments are percent-decoded, so a name containing whitespace is sent as `my%20file.txt`.
+/// A `%` that doesn't start a valid escape is taken literally.
+///
+/// A bearer token can be passed as a `token=...` argument or, for PUT, an
+/// `Authorization: Bearer ...` header. It is taken out of the request and verified right away,
+/// so it never shows up in the arguments or the log.
+#[derive(Debug)]
+pub struct Request {
+    pub(crate) method: String,
+    pub(crate) args: Vec<String>,
+    pub(crate) headers: HashMap<String, String>,
+    /// Claims of a valid bearer token, or why the token was rejected
+    pub(crate) token: Option<std::result::Result<Claims, String>>,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Read a single line, bounded by MAX_LINE_LENGTH. Returns `None` on end of stream.
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    let size = reader.by_ref().take(MAX_LINE_LENGTH).read_line(&mut line)?;
+
+    if size == 0 {
+        return Ok(None);
+    }
+
+    if !line.ends_with('\n') && size as u64 == MAX_LINE_LENGTH {
+        return Err(invalid("request line too long"));
+    }
+
+    Ok(Some(line.trim_end_matches(&['\r', '\n'][..]).to_string()))
+}
+
+/// Percent-decode a request argument, invalid escapes are kept as they are
+fn decode(arg: &str) -> String {
+    let bytes = arg.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8(decoded).unwrap_or_else(|_| arg.to_string())
+}
+
+/// Percent-encode whitespace, control characters and `%` in a name, so it can be sent back as a
+/// single field of a text response or a request argument
+/// # Examples
+/// ```
+/// assert_eq!(encode("my file.txt"), "my%20file.txt");
+/// ```
+pub fn encode(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+
+    for c in name.chars() {
+        if c == '%' || c.is_whitespace() || c.is_control() {
+            let mut buffer = [0; 4];
+            for byte in c.encode_utf8(&mut buffer).bytes() {
+                encoded.push_str(&format!("%{:02X}", byte));
+            }
+        } else {
+            encoded.push(c);
+        }
+    }
+
+    encoded
+}
+
+fn is_blank(line: &[u8]) -> bool {
+    line.iter().all(u8::is_ascii_whitespace)
+}
+
+impl Request {
+    /// Length of the blank lines at the start of the buffer, which `read` skips anyway
+    pub fn blank_lines(buffer: &[u8]) -> usize {
+        buffer
+            .split_inclusive(|b| *b == b'\n')
+            .take_while(|line| line.ends_with(b"\n") && is_blank(line))
+            .map(<[u8]>::len)
+            .sum()
+    }
+
+    /// Whether the buffer holds everything `read` needs before a request can be served: the
+    /// request line and, for PUT, the headers. A line too long to be valid counts as complete,
+    /// `read` then reports it.
+    /// # Examples
+    /// ```
+    /// assert!(Request::is_complete(b"LIST AFTP/1.0\r\n"));
+    /// assert!(!Request::is_complete(b"PUT a.txt AFTP/1.0\r\nContent-Length: 5\r\n"));
+    /// ```
+    pub fn is_complete(buffer: &[u8]) -> bool {
+        let mut lines = buffer[Request::blank_lines(buffer)..].split_inclusive(|b| *b == b'\n');
+        let too_long = |line: &[u8]| line.len() as u64 >= MAX_LINE_LENGTH;
+
+        let line = match lines.next() {
+            Some(line) if line.ends_with(b"\n") => line,
+            Some(line) => return too_long(line),
+            None => return false,
+        };
+
+        let method = line
+            .split(u8::is_ascii_whitespace)
+            .find(|token| !token.is_empty());
+        if method != Some(b"PUT") {
+            return true;
+        }
+
+        for (count, line) in lines.enumerate() {
+            if !line.ends_with(b"\n") {
+                return too_long(line);
+            }
+            if is_blank(line) || count == MAX_HEADERS {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Read the next request from the connection, returns `None` when the client has closed it
+    /// # Examples
+    /// ```
+    /// while let Some(request) = Request::read(&mut reader)? {
+    ///     ...
+    /// }
+    /// ```
+    pub fn read(reader: &mut impl BufRead) -> io::Result<Option<Request>> {
+        // blank lines between requests are ignored
+        let line = loop {
+            match read_line(reader)? {
+                None => return Ok(None),
+                Some(line) if line.trim().is_empty() => continue,
+                Some(line) => break line,
+            }
+        };
+
+        let mut tokens = line.split_whitespace().map(decode);
+        let method = tokens.next().unwrap();
+        let mut args: Vec<String> = tokens.collect();
+
+        if args.last().map(String::as_str) == Some(PROTOCOL) {
+            args.pop();
+        }
+
+        let mut token = args
+            .iter()
+            .position(|arg| arg.starts_with("token="))
+            .map(|index| args.remove(index)["token=".len()..].to_string());
+
+        let mut headers = HashMap::new();
+
+        if method == "PUT" {
+            for count in 0.. {
+                let line = match read_line(reader)? {
+                    None => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                    Some(line) => line,
+                };
+
+                if line.trim().is_empty() {
+                    break;
+                }
+                if count == MAX_HEADERS {
+                    return Err(invalid("too many headers"));
+                }
+
+                let mut pair = line.splitn(2, ':');
+                let name = pair.next().unwrap().trim().to_lowercase();
+                let value = pair.next().ok_or_else(|| invalid("malformed header"))?;
+                headers.insert(name, value.trim().to_string());
+            }
+        }
+
+        if let Some(authorization) = headers.remove("authorization") {
+            match authorization.strip_prefix("Bearer ") {
+                Some(bearer) => token = Some(bearer.trim().to_string()),
+                None => return Err(invalid("unsupported authorization scheme")),
+            }
+        }
+
+        Ok(Some(Request {
+            method,
+            args,
+            headers,
+            token: token.map(|token| Token::verify(&token)),
+        }))
+    }
+
+    /// First argument of the request line, usually the file name
+    pub fn target(&self) -> &str {
+        self.args.first().map(String::as_str).unwrap_or("")
+    }
+
+    /// Header value, header names are case insensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_lowercase()).map(String::as_str)
+    }
+
+    /// Whether a body follows the request without waiting for the server, a PUT with
+    /// `Expect: 100-continue` sends it only after a `100 Continue` response
+    pub fn body_follows(&self) -> bool {
+        self.method == "PUT"
+            && !self
+                .header("expect")
+                .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_escaped_arguments() {
+        assert_eq!(decode("my%20file.txt"), "my file.txt");
+        assert_eq!(decode("caf%C3%A9"), "café");
+        assert_eq!(decode("100%25"), "100%");
+    }
+
+    #[test]
+    fn keeps_invalid_escapes() {
+        assert_eq!(decode("50%off"), "50%off");
+        assert_eq!(decode("trailing%"), "trailing%");
+        assert_eq!(decode("short%2"), "short%2");
+        // an escape that isn't valid UTF-8 leaves the whole argument alone
+        assert_eq!(decode("bad%FF"), "bad%FF");
+    }
+
+    #[test]
+    fn encoded_names_round_trip() {
+        for name in &[
+            "plain.txt",
+            "my file.txt",
+            "tab\there",
+            "50%off",
+            "new\nline",
+            "café",
+        ] {
+            let encoded = encode(name);
+            assert!(!encoded.contains(char::is_whitespace));
+            assert_eq!(decode(&encoded), *name);
+        }
+        assert_eq!(encode("my file.txt"), "my%20file.txt");
+    }
+
+    #[test]
+    fn requests_are_complete_once_their_head_has_arrived() {
+        assert!(!Request::is_complete(b""));
+        assert!(!Request::is_complete(b"LI"));
+        assert!(!Request::is_complete(b"\r\n\r\nLIST AFTP/1.0"));
+        assert!(Request::is_complete(b"LIST AFTP/1.0\r\n"));
+        assert!(Request::is_complete(b"\r\n\nSTAT a.txt AFTP/1.0\n"));
+
+        let put = b"PUT a.txt AFTP/1.0\r\nContent-Length: 5\r\n";
+        assert!(!Request::is_complete(put));
+        assert!(!Request::is_complete(&[&put[..], b"\r"].concat()));
+        assert!(Request::is_complete(&[&put[..], b"\r\n"].concat()));
+        assert!(Request::is_complete(&[&put[..], b"\r\nhello"].concat()));
+    }
+
+    #[test]
+    fn overlong_requests_are_complete() {
+        let line = vec![b'A'; MAX_LINE_LENGTH as usize];
+        assert!(Request::is_complete(&line));
+        assert!(!Request::is_complete(&line[1..]));
+
+        let mut put = b"PUT a.txt AFTP/1.0\r\n".to_vec();
+        for _ in 0..=MAX_HEADERS {
+            put.extend_from_slice(b"X-Padding: 1\r\n");
+        }
+        assert!(Request::is_complete(&put));
+    }
+
+    #[test]
+    fn blank_lines_before_a_request_are_counted() {
+        assert_eq!(Request::blank_lines(b"\r\n \nLIST\n"), 4);
+        assert_eq!(Request::blank_lines(b"\r\n  "), 2);
+        assert_eq!(Request::blank_lines(b"LIST\n\n"), 0);
+    }
+
+    #[test]
+    fn request_line_arguments_are_decoded() {
+        let mut input = &b"GET my%20file.txt AFTP/1.0\r\n"[..];
+        let request = Request::read(&mut input).unwrap().unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.target(), "my file.txt");
+    }
+}
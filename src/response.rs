@@ -0,0 +1,150 @@
+use serde::Serialize;
+
+use std::io;
+use std::io::Write;
+
+/// Output format for response bodies, negotiated with `format=text|json` on the request line
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+impl Format {
+    pub fn parse(value: &str) -> Option<Format> {
+        match value {
+            "text" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Continue,
+    Ok,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    Conflict,
+    RequestTimeout,
+    PayloadTooLarge,
+    Locked,
+    TooManyRequests,
+    InternalError,
+    InsufficientStorage,
+    QuotaExceeded,
+    ServiceUnavailable,
+}
+
+impl Status {
+    pub fn code(self) -> u16 {
+        match self {
+            Status::Continue => 100,
+            Status::Ok => 200,
+            Status::BadRequest => 400,
+            Status::Unauthorized => 401,
+            Status::Forbidden => 403,
+            Status::NotFound => 404,
+            Status::Conflict => 409,
+            Status::RequestTimeout => 408,
+            Status::PayloadTooLarge => 413,
+            Status::Locked => 423,
+            Status::TooManyRequests => 429,
+            Status::InternalError => 500,
+            Status::QuotaExceeded => 422,
+            Status::InsufficientStorage => 507,
+            Status::ServiceUnavailable => 503,
+        }
+    }
+
+    pub fn reason(self) -> &'static str {
+        match self {
+            Status::Continue => "Continue",
+            Status::Ok => "OK",
+            Status::BadRequest => "Bad Request",
+            Status::Unauthorized => "Unauthorized",
+            Status::Forbidden => "Forbidden",
+            Status::NotFound => "Not Found",
+            Status::Conflict => "Conflict",
+            Status::RequestTimeout => "Request Timeout",
+            Status::PayloadTooLarge => "Payload Too Large",
+            Status::Locked => "Locked",
+            Status::TooManyRequests => "Too Many Requests",
+            Status::InternalError => "Internal Server Error",
+            Status::InsufficientStorage => "Insufficient Storage",
+            Status::QuotaExceeded => "Quota Exceeded",
+            Status::ServiceUnavailable => "Service Unavailable",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    status: u16,
+    error: &'a str,
+    message: &'a str,
+}
+
+/// Write a status line and headers for a body of `content_length` bytes, the body follows the
+/// blank line that ends the headers
+pub fn write_head(
+    stream: &mut impl Write,
+    status: Status,
+    headers: &[(&str, String)],
+    content_length: u64,
+) -> io::Result<()> {
+    let mut head = format!(
+        "AFTP/1.0 {} {}\nContent-Length: {}\n",
+        status.code(),
+        status.reason(),
+        content_length
+    );
+
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\n", name, value));
+    }
+
+    head.push('\n');
+    stream.write_all(head.as_bytes())
+}
+
+/// Write a status line, the given headers and a body framed by `Content-Length`
+/// # Examples
+/// ```
+/// response::write(&mut stream, Status::Ok, &[("Total-Count", total.to_string())], &body)?;
+/// ```
+pub fn write(
+    stream: &mut impl Write,
+    status: Status,
+    headers: &[(&str, String)],
+    body: &str,
+) -> io::Result<()> {
+    write_head(stream, status, headers, body.len() as u64)?;
+    stream.write_all(body.as_bytes())
+}
+
+/// Write an error response, the message is sent as plain text or as a JSON object
+pub fn write_error(
+    stream: &mut impl Write,
+    format: Format,
+    status: Status,
+    message: &str,
+) -> io::Result<()> {
+    let body = match format {
+        Format::Text => format!("{}\n", message),
+        Format::Json => {
+            let error = ErrorBody {
+                status: status.code(),
+                error: status.reason(),
+                message,
+            };
+            serde_json::to_string(&error).unwrap() + "\n"
+        }
+    };
+
+    write(stream, status, &[], &body)
+}
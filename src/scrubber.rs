@@ -0,0 +1,185 @@
+use crate::compression;
+use crate::config::Config;
+use crate::file_manager::{FileManager, DATABASE};
+use crate::hash;
+
+use chrono::Utc;
+use rusqlite::{params, Connection};
+use slog::Logger;
+use slog::*;
+use std::fs::File;
+use std::thread;
+use std::time::Duration;
+
+/// A file whose content no longer matches its indexed hash
+#[derive(Debug)]
+pub struct Corruption {
+    pub filename: String,
+    pub algorithm: String,
+    pub expected: String,
+    pub actual: String,
+    /// Unix timestamp of the scrub that found it
+    pub detected: i64,
+}
+
+/// Re-hashes the indexed files every `scrub_interval_secs` and compares the result with the
+/// hash in the index. Mismatches are logged and recorded in the `corruption` table until a
+/// later pass finds the file intact again, or the file is replaced or deleted.
+pub struct Scrubber;
+
+impl Scrubber {
+    /// Create the corruption table, should be called once at startup
+    /// # Examples
+    /// ```
+    /// Scrubber::initialize(log.clone());
+    /// ```
+    pub fn initialize(log: Logger) {
+        info!(log, "Initializing integrity scrubber");
+        let conn = Connection::open(DATABASE).unwrap();
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS corruption (
+                  filename        TEXT PRIMARY KEY,
+                  algorithm       TEXT NOT NULL,
+                  expected        TEXT NOT NULL,
+                  actual          TEXT NOT NULL,
+                  detected        INTEGER NOT NULL
+                  )",
+            params![],
+        )
+        .unwrap();
+    }
+
+    /// Scrub on a thread of its own, unless `scrub_interval_secs` is 0
+    pub fn spawn(log: Logger) {
+        let interval = Config::get().scrub_interval_secs;
+        if interval == 0 {
+            return;
+        }
+
+        info!(log, "Scrubbing files every {}s", interval);
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(interval));
+            Scrubber::scrub(log.clone());
+        });
+    }
+
+    /// Check every indexed file once
+    pub fn scrub(log: Logger) {
+        let files = FileManager::get().lock().unwrap().as_ref().unwrap().list();
+        let conn = Connection::open(DATABASE).unwrap();
+        let mut corrupted = 0;
+
+        info!(log, "Scrubbing {} files", files.len());
+
+        for indexed in &files {
+            let actual = match File::open(&indexed.path)
+                .and_then(|file| compression::reader(file, indexed.encoding))
+                .and_then(|mut content| hash::digest(&mut content, indexed.algorithm))
+            {
+                Ok(actual) => actual,
+                // gone since the listing, the watcher or a DELETE takes care of it
+                Err(_) => continue,
+            };
+
+            // replaced while being hashed, the new content was hashed on upload
+            let current = FileManager::get()
+                .lock()
+                .unwrap()
+                .as_ref()
+                .unwrap()
+                .find(&indexed.filename);
+            match current {
+                Some(current) if current.hash == indexed.hash => {}
+                _ => continue,
+            }
+
+            if actual == indexed.hash {
+                conn.execute(
+                    "DELETE FROM corruption WHERE filename = ?1",
+                    params![indexed.filename],
+                )
+                .unwrap();
+                continue;
+            }
+
+            corrupted += 1;
+            error!(
+                log,
+                "Corrupted file {}: {} hash is {}, expected {}",
+                indexed.filename,
+                indexed.algorithm,
+                actual,
+                indexed.hash
+            );
+            conn.execute(
+                "INSERT INTO corruption (filename, algorithm, expected, actual, detected)
+                      VALUES (?1, ?2, ?3, ?4, ?5)
+                      ON CONFLICT(filename) DO UPDATE
+                      SET algorithm = ?2, expected = ?3, actual = ?4, detected = ?5",
+                params![
+                    indexed.filename,
+                    indexed.algorithm.name(),
+                    indexed.hash,
+                    actual,
+                    Utc::now().timestamp()
+                ],
+            )
+            .unwrap();
+        }
+
+        // files that were deleted or replaced aren't corrupted anymore
+        let mut statement = conn
+            .prepare("SELECT filename, expected FROM corruption")
+            .unwrap();
+        let recorded: Vec<(String, String)> = statement
+            .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .filter_map(|row| row.ok())
+            .collect();
+        for (filename, expected) in recorded {
+            let stale = !files
+                .iter()
+                .any(|file| file.filename == filename && file.hash == expected);
+            if stale {
+                conn.execute(
+                    "DELETE FROM corruption WHERE filename = ?1",
+                    params![filename],
+                )
+                .unwrap();
+            }
+        }
+
+        info!(
+            log,
+            "Scrubbed {} files, {} corrupted",
+            files.len(),
+            corrupted
+        );
+    }
+
+    /// Corrupted files found by the last scrubs
+    /// # Examples
+    /// ```
+    /// for corruption in Scrubber::corrupted()? { ... }
+    /// ```
+    pub fn corrupted() -> rusqlite::Result<Vec<Corruption>> {
+        let conn = Connection::open(DATABASE)?;
+        let mut statement = conn.prepare(
+            "SELECT filename, algorithm, expected, actual, detected FROM corruption
+              ORDER BY filename",
+        )?;
+
+        let rows = statement.query_map(params![], |row| {
+            Ok(Corruption {
+                filename: row.get(0)?,
+                algorithm: row.get(1)?,
+                expected: row.get(2)?,
+                actual: row.get(3)?,
+                detected: row.get(4)?,
+            })
+        })?;
+
+        rows.collect()
+    }
+}
@@ -0,0 +1,473 @@
+use crate::config::Config;
+use crate::ip_filter::IpFilter;
+use crate::limits::Limits;
+use crate::pool::ThreadPool;
+use crate::response::Status;
+use crate::session::Session;
+
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Token, Waker};
+use rustls::ServerConfig;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook_mio::v0_8::Signals;
+use slog::Logger;
+use slog::*;
+use std::collections::HashMap;
+use std::io;
+use std::net::TcpListener;
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+const SIGNAL: Token = Token(2);
+// tokens from here on are handed out to connections
+const FIRST_CONNECTION: usize = 3;
+
+// how often idle connections are checked for their timeout
+const TICK: Duration = Duration::from_secs(1);
+
+// pause before accepting again after accepting failed, e.g. when out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// A connection waiting for its next request
+struct Parked {
+    session: Session,
+    since: Instant,
+}
+
+/// Hands a session back to the server once a worker is done with it, even when serving it
+/// panicked, so the server's count of active sessions stays right
+struct Returning {
+    session: Option<Session>,
+    returned: Sender<Session>,
+    waker: Arc<Waker>,
+}
+
+impl Drop for Returning {
+    fn drop(&mut self) {
+        if let Some(mut session) = self.session.take() {
+            // whatever state a panic left the connection in, it isn't served any further
+            if thread::panicking() {
+                session.open = false;
+            }
+            if self.returned.send(session).is_ok() {
+                let _ = self.waker.wake();
+            }
+        }
+    }
+}
+
+/// Event driven server core.
+///
+/// Idle connections are parked in a `mio::Poll` and don't occupy a thread. Whatever a client
+/// sends is read without blocking until a whole request line, and for PUT its headers, has
+/// arrived. Only then is the session handed to the worker pool, which serves the request with
+/// blocking I/O and hands the session back to be parked again.
+///
+/// On SIGINT or SIGTERM the listener is closed, idle connections are ended and the sessions
+/// still being served get until the shutdown deadline to finish.
+pub struct Server {
+    poll: Poll,
+    // closed once shutting down
+    listener: Option<TcpListener>,
+    pool: ThreadPool,
+    tls: Option<Arc<ServerConfig>>,
+    parked: HashMap<Token, Parked>,
+    next_token: usize,
+    waker: Arc<Waker>,
+    signals: Signals,
+    // sessions handed back by the workers, ended or not
+    returned_sender: Sender<Session>,
+    returned: Receiver<Session>,
+    // sessions currently with a worker
+    active: usize,
+    // accepting failed, connections are accepted again from then on
+    accept_paused_until: Option<Instant>,
+    shutdown_deadline: Option<Instant>,
+    log: Logger,
+}
+
+impl Server {
+    /// Create the server core for an already bound listener
+    /// # Examples
+    /// ```
+    /// let mut server = Server::new(listener, pool, tls, log.clone()).unwrap();
+    /// server.run().unwrap();
+    /// ```
+    pub fn new(
+        listener: TcpListener,
+        pool: ThreadPool,
+        tls: Option<Arc<ServerConfig>>,
+        log: Logger,
+    ) -> io::Result<Server> {
+        let poll = Poll::new()?;
+
+        listener.set_nonblocking(true)?;
+        poll.registry().register(
+            &mut SourceFd(&listener.as_raw_fd()),
+            LISTENER,
+            Interest::READABLE,
+        )?;
+
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let (returned_sender, returned) = channel();
+
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        poll.registry()
+            .register(&mut signals, SIGNAL, Interest::READABLE)?;
+
+        Ok(Server {
+            poll,
+            listener: Some(listener),
+            pool,
+            tls,
+            parked: HashMap::new(),
+            next_token: FIRST_CONNECTION,
+            waker,
+            signals,
+            returned_sender,
+            returned,
+            active: 0,
+            accept_paused_until: None,
+            shutdown_deadline: None,
+            log,
+        })
+    }
+
+    /// Run the event loop, returns once the server has shut down
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(1024);
+
+        loop {
+            let timeout = match self.accept_paused_until {
+                Some(until) => until.saturating_duration_since(Instant::now()).min(TICK),
+                None => TICK,
+            };
+
+            if let Err(e) = self.poll.poll(&mut events, Some(timeout)) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => self.accept(),
+                    WAKER => self.park_returned(),
+                    SIGNAL => self.begin_shutdown(),
+                    token => self.readable(token),
+                }
+            }
+
+            if let Some(deadline) = self.shutdown_deadline {
+                if self.active == 0 {
+                    info!(self.log, "All connections finished");
+                    return Ok(());
+                }
+                if Instant::now() >= deadline {
+                    warn!(
+                        self.log,
+                        "Shutdown deadline reached with {} active connections", self.active
+                    );
+                    return Ok(());
+                }
+            }
+
+            // pending connections don't signal the listener again, so they are picked up here
+            if self
+                .accept_paused_until
+                .is_some_and(|until| Instant::now() >= until)
+            {
+                self.accept_paused_until = None;
+                self.accept();
+            }
+
+            self.expire_idle();
+        }
+    }
+
+    /// Stop accepting connections and end idle ones, a second signal ends the server at once
+    fn begin_shutdown(&mut self) {
+        let signals: Vec<i32> = self.signals.pending().collect();
+        if signals.is_empty() {
+            return;
+        }
+
+        if self.shutdown_deadline.is_some() {
+            warn!(
+                self.log,
+                "Received another signal, shutting down immediately"
+            );
+            self.shutdown_deadline = Some(Instant::now());
+            return;
+        }
+
+        let timeout = Duration::from_secs(Config::get().shutdown_timeout_secs);
+        info!(
+            self.log,
+            "Received signal {:?}, shutting down, waiting up to {:?} for {} active connections",
+            signals,
+            timeout,
+            self.active
+        );
+        self.shutdown_deadline = Some(Instant::now() + timeout);
+
+        if let Some(listener) = self.listener.take() {
+            let _ = self
+                .poll
+                .registry()
+                .deregister(&mut SourceFd(&listener.as_raw_fd()));
+        }
+
+        let tokens: Vec<Token> = self.parked.keys().copied().collect();
+        for token in tokens {
+            if let Some(mut session) = self.unpark(token) {
+                session.reject(Status::ServiceUnavailable, "server shutting down");
+            }
+        }
+    }
+
+    fn accept(&mut self) {
+        if self.accept_paused_until.is_some() {
+            return;
+        }
+
+        loop {
+            let accepted = match &self.listener {
+                Some(listener) => listener.accept(),
+                None => return,
+            };
+
+            match accepted {
+                Ok((stream, peer)) => {
+                    if !IpFilter::allows(peer.ip()) {
+                        // dropping the stream closes it, nothing is sent
+                        warn!(
+                            self.log,
+                            "Refused connection from {} ({} refused so far)",
+                            peer,
+                            IpFilter::rejected()
+                        );
+                        continue;
+                    }
+
+                    info!(self.log, "New connection: {}", peer);
+
+                    // accepted sockets start out blocking, waiting sessions switch to non-blocking
+                    let mut session = match Session::new(stream, self.tls.clone()) {
+                        Ok(session) => session,
+                        Err(e) => {
+                            error!(self.log, "Failed to set up connection {}: {}", peer, e);
+                            continue;
+                        }
+                    };
+
+                    match Limits::connect(peer.ip()) {
+                        Some(slot) => {
+                            session.connection = Some(slot);
+                            self.wait(session);
+                        }
+                        None => {
+                            warn!(self.log, "Too many connections from {}", peer.ip());
+                            session.reject(Status::TooManyRequests, "too many connections");
+                        }
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    // sessions keep their locks, they release them when they end
+                    error!(
+                        self.log,
+                        "Failed to accept connection, retrying in {:?}: {}", ACCEPT_BACKOFF, e
+                    );
+                    self.accept_paused_until = Some(Instant::now() + ACCEPT_BACKOFF);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Let a session wait for its next request, it's served right away if that has arrived
+    fn wait(&mut self, mut session: Session) {
+        if let Err(e) = session.set_nonblocking(true) {
+            error!(
+                self.log,
+                "Failed to park connection {}: {}", session.peer, e
+            );
+            return;
+        }
+
+        match session.buffer_request() {
+            Ok(true) => self.dispatch(session),
+            Ok(false) => self.park(session),
+            Err(e) => info!(self.log, "Connection with {} failed: {}", session.peer, e),
+        }
+    }
+
+    fn park(&mut self, session: Session) {
+        let token = Token(self.next_token);
+        self.next_token += 1;
+
+        let fd = session.reader_fd();
+        let registered =
+            self.poll
+                .registry()
+                .register(&mut SourceFd(&fd), token, Interest::READABLE);
+
+        match registered {
+            Ok(()) => {
+                self.parked.insert(
+                    token,
+                    Parked {
+                        session,
+                        since: Instant::now(),
+                    },
+                );
+            }
+            Err(e) => error!(
+                self.log,
+                "Failed to park connection {}: {}", session.peer, e
+            ),
+        }
+    }
+
+    fn unpark(&mut self, token: Token) -> Option<Session> {
+        let parked = self.parked.remove(&token)?;
+        let fd = parked.session.reader_fd();
+        let _ = self.poll.registry().deregister(&mut SourceFd(&fd));
+        Some(parked.session)
+    }
+
+    fn park_returned(&mut self) {
+        while let Ok(mut session) = self.returned.try_recv() {
+            self.active -= 1;
+
+            if !session.open {
+                continue;
+            }
+
+            if self.shutdown_deadline.is_some() {
+                session.reject(Status::ServiceUnavailable, "server shutting down");
+            } else {
+                self.wait(session);
+            }
+        }
+    }
+
+    /// Read what a parked connection has sent, it's served once a whole request has arrived
+    fn readable(&mut self, token: Token) {
+        let parked = match self.parked.get_mut(&token) {
+            Some(parked) => parked,
+            None => return,
+        };
+
+        let started = parked.session.has_partial_request();
+        let buffered = parked.session.buffer_request();
+
+        // a request that has started arriving gets the read timeout from here on
+        if !started {
+            parked.since = Instant::now();
+        }
+
+        match buffered {
+            Ok(false) => {}
+            Ok(true) => {
+                if let Some(session) = self.unpark(token) {
+                    self.dispatch(session);
+                }
+            }
+            Err(e) => {
+                if let Some(session) = self.unpark(token) {
+                    info!(self.log, "Connection with {} failed: {}", session.peer, e);
+                }
+            }
+        }
+    }
+
+    /// Hand a session with a complete request to the worker pool
+    fn dispatch(&mut self, mut session: Session) {
+        if let Err(e) = session.set_nonblocking(false) {
+            error!(
+                self.log,
+                "Failed to serve connection {}: {}", session.peer, e
+            );
+            return;
+        }
+
+        // the session stays reachable from here in case the pool can't take the job
+        let slot = Arc::new(Mutex::new(None));
+        let peer = session.peer;
+        *slot.lock().unwrap() = Some(session);
+
+        let job_slot = slot.clone();
+        let log = self.log.clone();
+        let returned = self.returned_sender.clone();
+        let waker = self.waker.clone();
+
+        let job = self.pool.execute(move || {
+            let mut returning = Returning {
+                session: job_slot.lock().unwrap().take(),
+                returned,
+                waker,
+            };
+            if let Some(session) = returning.session.as_mut() {
+                session.serve(&log);
+            }
+        });
+
+        if job.is_ok() {
+            self.active += 1;
+        } else if let Some(mut session) = slot.lock().unwrap().take() {
+            warn!(self.log, "Server busy, rejecting connection from {}", peer);
+            session.reject(Status::ServiceUnavailable, "server busy");
+        }
+    }
+
+    fn expire_idle(&mut self) {
+        let idle_timeout = Duration::from_secs(Config::get().idle_timeout_secs);
+
+        let expired: Vec<Token> = self
+            .parked
+            .iter()
+            .filter(|(_, parked)| parked.since.elapsed() >= idle_timeout)
+            .map(|(token, _)| *token)
+            .collect();
+
+        for token in expired {
+            if let Some(mut session) = self.unpark(token) {
+                info!(
+                    self.log,
+                    "Idle timeout, terminating connection with {}", session.peer
+                );
+                session.reject(Status::RequestTimeout, "idle timeout");
+            }
+        }
+
+        // a client may not take longer to send the rest of a request than to read a response
+        let read_timeout = Duration::from_secs(Config::get().read_timeout_secs);
+
+        let stalled: Vec<Token> = self
+            .parked
+            .iter()
+            .filter(|(_, parked)| {
+                parked.session.has_partial_request() && parked.since.elapsed() >= read_timeout
+            })
+            .map(|(token, _)| *token)
+            .collect();
+
+        for token in stalled {
+            if let Some(mut session) = self.unpark(token) {
+                info!(
+                    self.log,
+                    "Request timeout, terminating connection with {}", session.peer
+                );
+                session.reject(Status::RequestTimeout, "request timeout");
+            }
+        }
+    }
+}
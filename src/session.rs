@@ -0,0 +1,304 @@
+use crate::command::Command;
+use crate::config::Config;
+use crate::file_manager::SINGLETON;
+use crate::limits::{ConnectionSlot, Limits};
+use crate::request::Request;
+use crate::response;
+use crate::response::{Format, Status};
+use crate::tls::TlsStream;
+
+use rustls::ServerConfig;
+use slog::Logger;
+use slog::*;
+use std::io;
+use std::io::{BufRead, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Connection a session is served over, plain TCP or TLS
+pub trait Stream: Read + Write + AsRawFd + Send {
+    /// Switch the underlying socket between blocking and non-blocking reads and writes
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Stream for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+// bytes read from the connection at a time
+const CHUNK_SIZE: usize = 8192;
+
+/// Buffered reader over a connection. Unlike `BufReader` it can add to a partly read request,
+/// so the server can collect a request with non-blocking reads before a worker serves it.
+pub struct Reader {
+    stream: Box<dyn Stream>,
+    buffer: Vec<u8>,
+    // start of the data not read yet
+    position: usize,
+}
+
+impl Reader {
+    fn new(stream: Box<dyn Stream>) -> Reader {
+        Reader {
+            stream,
+            buffer: Vec::new(),
+            position: 0,
+        }
+    }
+
+    /// Data read from the connection but not consumed yet
+    pub fn buffer(&self) -> &[u8] {
+        &self.buffer[self.position..]
+    }
+
+    pub fn get_ref(&self) -> &dyn Stream {
+        self.stream.as_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut Box<dyn Stream> {
+        &mut self.stream
+    }
+
+    /// Read more data from the connection after what is already buffered, returns the number of
+    /// bytes read, 0 at the end of the stream
+    fn read_more(&mut self) -> io::Result<usize> {
+        if self.position > 0 {
+            self.buffer.drain(..self.position);
+            self.position = 0;
+        }
+
+        let length = self.buffer.len();
+        self.buffer.resize(length + CHUNK_SIZE, 0);
+        let read = self.stream.read(&mut self.buffer[length..]);
+        self.buffer.truncate(length + *read.as_ref().unwrap_or(&0));
+        read
+    }
+}
+
+impl Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // large reads of an upload body skip the buffer
+        if self.buffer().is_empty() && buf.len() >= CHUNK_SIZE {
+            return self.stream.read(buf);
+        }
+
+        let size = self.fill_buf()?.read(buf)?;
+        self.consume(size);
+        Ok(size)
+    }
+}
+
+impl BufRead for Reader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.buffer().is_empty() {
+            self.read_more()?;
+        }
+        Ok(self.buffer())
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.position = (self.position + amount).min(self.buffer.len());
+    }
+}
+
+/// State of a single client connection, lives as long as the connection is kept alive.
+/// Dropping the session closes the connection and releases its locks.
+pub struct Session {
+    reader: Reader,
+    pub(crate) peer: SocketAddr,
+    /// Files locked by this connection, released when it ends
+    pub(crate) locked_files: Vec<String>,
+    /// Cleared by QUIT or after a failed upload, ends the session after the current request
+    pub(crate) open: bool,
+    /// User the client logged in as
+    pub(crate) user: Option<String>,
+    /// Counts the connection against the per-address limit while the session lives
+    pub(crate) connection: Option<ConnectionSlot>,
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+impl Session {
+    /// Set up a session for an accepted connection, wrapped in TLS when a TLS configuration is given
+    pub fn new(stream: TcpStream, tls: Option<Arc<ServerConfig>>) -> io::Result<Session> {
+        // a client that stalls halfway through a request may not hold on to its worker forever
+        let config = Config::get();
+        stream.set_read_timeout(Some(Duration::from_secs(config.read_timeout_secs)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(config.write_timeout_secs)))?;
+
+        let peer = stream.peer_addr()?;
+        let stream: Box<dyn Stream> = match tls {
+            Some(tls) => Box::new(TlsStream::new(tls, stream)?),
+            None => Box::new(stream),
+        };
+
+        Ok(Session {
+            peer,
+            reader: Reader::new(stream),
+            locked_files: Vec::new(),
+            open: true,
+            user: None,
+            connection: None,
+        })
+    }
+
+    /// Buffered reader over the connection, used to read requests and upload bodies
+    pub fn reader(&mut self) -> &mut Reader {
+        &mut self.reader
+    }
+
+    /// The connection to write responses to
+    pub fn stream(&mut self) -> &mut Box<dyn Stream> {
+        self.reader.get_mut()
+    }
+
+    /// File descriptor of the connection, used to wait for it to become readable
+    pub fn reader_fd(&self) -> RawFd {
+        self.reader.get_ref().as_raw_fd()
+    }
+
+    /// Switch the connection to non-blocking I/O while it waits in the server, a worker serves
+    /// it with blocking I/O and timeouts
+    pub fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        self.reader.get_ref().set_nonblocking(nonblocking)
+    }
+
+    /// Whether part of the next request has arrived
+    pub fn has_partial_request(&self) -> bool {
+        !self.reader.buffer().is_empty()
+    }
+
+    /// Read whatever the client has sent without blocking, returns true once the next request
+    /// can be served without waiting for the client, or the client has closed the connection
+    pub fn buffer_request(&mut self) -> io::Result<bool> {
+        loop {
+            let blank = Request::blank_lines(self.reader.buffer());
+            self.reader.consume(blank);
+
+            if Request::is_complete(self.reader.buffer()) {
+                return Ok(true);
+            }
+
+            match self.reader.read_more() {
+                // serving the request finds out the connection was closed
+                Ok(0) => return Ok(true),
+                Ok(_) => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Serve the requests that have arrived completely. Should only be called once
+    /// `buffer_request` returned true, returns false when the session has ended.
+    pub fn serve(&mut self, log: &Logger) -> bool {
+        while self.open {
+            self.serve_request(log);
+
+            // the rest is collected by the server without holding on to a worker
+            if !Request::is_complete(self.reader.buffer()) {
+                break;
+            }
+        }
+
+        self.open
+    }
+
+    fn serve_request(&mut self, log: &Logger) {
+        let peer = self.peer;
+
+        let request = match Request::read(self.reader()) {
+            Ok(Some(request)) => request,
+            Ok(None) => {
+                info!(log, "Connection closed by client: {}", peer);
+                self.open = false;
+                return;
+            }
+            Err(ref e) if is_timeout(e) => {
+                info!(log, "Request timeout, terminating connection with {}", peer);
+                self.reject(Status::RequestTimeout, "request timeout");
+                return;
+            }
+            Err(e) => {
+                error!(log, "Invalid request from {}: {}", peer, e);
+                self.reject(Status::BadRequest, &format!("invalid request: {}", e));
+                return;
+            }
+        };
+
+        // never log passwords
+        if request.method == "LOGIN" {
+            info!(log, "Request from {}: LOGIN {}", peer, request.target());
+        } else {
+            info!(
+                log,
+                "Request from {}: {} {:?}", peer, request.method, request.args
+            );
+        }
+
+        if !Limits::allow_request(peer.ip()) {
+            warn!(log, "Request rate exceeded by {}", peer);
+            // an upload body can't be skipped, so the session ends
+            if request.body_follows() {
+                self.open = false;
+            }
+            let _ = response::write_error(
+                self.stream(),
+                Format::Text,
+                Status::TooManyRequests,
+                "request rate exceeded",
+            );
+            return;
+        }
+
+        let command = Command::from_request(request);
+
+        match command.execute_method(self, log.clone()) {
+            Ok(()) => {}
+            Err(ref e) if is_timeout(e) => {
+                info!(log, "Request timeout, terminating connection with {}", peer);
+                self.reject(Status::RequestTimeout, "request timeout");
+            }
+            Err(e) => {
+                error!(
+                    log,
+                    "An error occurred, terminating connection with {}: {}", peer, e
+                );
+                self.open = false;
+            }
+        }
+    }
+
+    /// Answer with an error status and end the session
+    pub fn reject(&mut self, status: Status, message: &str) {
+        let _ = response::write_error(self.stream(), Format::Text, status, message);
+        self.open = false;
+    }
+
+    fn release_locks(&mut self) {
+        // also runs while unwinding from a panicked request, so a poisoned lock is tolerated
+        let mut instance = match SINGLETON.lock() {
+            Ok(instance) => instance,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        if let Some(manager) = instance.as_mut() {
+            for file_name in self.locked_files.drain(..) {
+                manager.lock_file(&file_name, false);
+            }
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.release_locks();
+    }
+}
@@ -0,0 +1,271 @@
+use crate::config::TlsConfig;
+use crate::session::Stream;
+
+use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient};
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificates found in {}", path)));
+    }
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &str) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(invalid(format!("no private key found in {}", path))),
+        }
+    }
+}
+
+/// Build the rustls server configuration from the `[tls]` section of the configuration
+/// # Examples
+/// ```
+/// let tls = tls::server_config(&config.tls.unwrap())?;
+/// ```
+pub fn server_config(config: &TlsConfig) -> io::Result<Arc<ServerConfig>> {
+    let certs = load_certs(&config.cert_file)?;
+    let key = load_key(&config.key_file)?;
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+
+    let builder = match &config.client_ca_file {
+        Some(client_ca_file) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(client_ca_file)? {
+                roots
+                    .add(&cert)
+                    .map_err(|e| invalid(format!("invalid CA certificate: {}", e)))?;
+            }
+
+            if config.require_client_cert {
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+            } else {
+                builder
+                    .with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(roots))
+            }
+        }
+        None if config.require_client_cert => {
+            return Err(invalid(
+                "require_client_cert needs a client_ca_file".to_string(),
+            ))
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let server_config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid(format!("invalid certificate or key: {}", e)))?;
+
+    Ok(Arc::new(server_config))
+}
+
+/// TLS connection, the handshake happens on the first read or write
+pub struct TlsStream {
+    inner: StreamOwned<ServerConnection, TcpStream>,
+}
+
+impl TlsStream {
+    pub fn new(config: Arc<ServerConfig>, stream: TcpStream) -> io::Result<TlsStream> {
+        let connection = ServerConnection::new(config).map_err(io::Error::other)?;
+
+        Ok(TlsStream {
+            inner: StreamOwned::new(connection, stream),
+        })
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl AsRawFd for TlsStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.sock.as_raw_fd()
+    }
+}
+
+impl Stream for TlsStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.inner.sock.set_nonblocking(nonblocking)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::{ClientConfig, ClientConnection, ServerName};
+    use std::convert::TryFrom;
+    use std::io::BufRead;
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    // self-signed test CA with a server certificate for localhost and a client certificate
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn tls_config(client_ca: bool, require_client_cert: bool) -> TlsConfig {
+        TlsConfig {
+            cert_file: fixture("server.pem"),
+            key_file: fixture("server-key.pem"),
+            client_ca_file: if client_ca {
+                Some(fixture("ca.pem"))
+            } else {
+                None
+            },
+            require_client_cert,
+        }
+    }
+
+    fn client_config(with_cert: bool) -> Arc<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(&fixture("ca.pem")).unwrap() {
+            roots.add(&cert).unwrap();
+        }
+
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let config = if with_cert {
+            builder
+                .with_single_cert(
+                    load_certs(&fixture("client.pem")).unwrap(),
+                    load_key(&fixture("client-key.pem")).unwrap(),
+                )
+                .unwrap()
+        } else {
+            builder.with_no_client_auth()
+        };
+        Arc::new(config)
+    }
+
+    // send a line to a TlsStream served on a local port and read the line it answers with
+    fn exchange(server: Arc<ServerConfig>, with_cert: bool) -> io::Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+
+        let serving = thread::spawn(move || -> io::Result<()> {
+            let (stream, _) = listener.accept()?;
+            stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+            let mut stream = io::BufReader::new(TlsStream::new(server, stream)?);
+            let mut line = String::new();
+            stream.read_line(&mut line)?;
+            stream
+                .get_mut()
+                .write_all(format!("echo {}", line).as_bytes())?;
+            stream.get_mut().flush()
+        });
+
+        let stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let connection = ClientConnection::new(
+            client_config(with_cert),
+            ServerName::try_from("localhost").unwrap(),
+        )
+        .map_err(io::Error::other)?;
+        let mut client = io::BufReader::new(StreamOwned::new(connection, stream));
+
+        client.get_mut().write_all(b"ping\n")?;
+        let mut line = String::new();
+        let read = client.read_line(&mut line);
+        let _ = serving.join();
+
+        match read? {
+            0 => Err(io::ErrorKind::UnexpectedEof.into()),
+            _ => Ok(line),
+        }
+    }
+
+    #[test]
+    fn loads_certificate_and_key() {
+        assert!(server_config(&tls_config(false, false)).is_ok());
+        assert!(server_config(&tls_config(true, true)).is_ok());
+    }
+
+    #[test]
+    fn rejects_missing_or_invalid_files() {
+        let mut config = tls_config(false, false);
+        config.key_file = fixture("missing.pem");
+        assert_eq!(
+            server_config(&config).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+
+        let mut config = tls_config(false, false);
+        config.cert_file = fixture("server-key.pem");
+        assert_eq!(
+            server_config(&config).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+
+        let mut config = tls_config(false, false);
+        config.key_file = fixture("server.pem");
+        assert_eq!(
+            server_config(&config).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn requiring_client_certs_needs_a_ca() {
+        assert_eq!(
+            server_config(&tls_config(false, true)).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn handshake_without_client_auth() {
+        let server = server_config(&tls_config(false, false)).unwrap();
+        assert_eq!(exchange(server, false).unwrap(), "echo ping\n");
+    }
+
+    #[test]
+    fn required_client_cert() {
+        let server = server_config(&tls_config(true, true)).unwrap();
+        assert_eq!(exchange(server.clone(), true).unwrap(), "echo ping\n");
+        assert!(exchange(server, false).is_err());
+    }
+
+    #[test]
+    fn optional_client_cert() {
+        let server = server_config(&tls_config(true, false)).unwrap();
+        assert_eq!(exchange(server.clone(), true).unwrap(), "echo ping\n");
+        assert_eq!(exchange(server, false).unwrap(), "echo ping\n");
+    }
+}
@@ -0,0 +1,265 @@
+use crate::blobs::Blobs;
+use crate::compression::Encoding;
+use crate::config::Config;
+use crate::file_manager::{add_column, TFile, DATABASE};
+use crate::hash::Algorithm;
+
+use chrono::Utc;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::Serialize;
+use slog::Logger;
+use slog::*;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{env, fs, thread};
+
+// deleted files, named by their id in the trash table
+const TRASH_ROOT: &str = "./trash";
+
+// longest wait between two purges of expired files
+const PURGE_INTERVAL_SECS: u64 = 3600;
+
+/// A deleted file waiting in the trash
+#[derive(Debug, Clone, Serialize)]
+pub struct Trashed {
+    pub(crate) filename: String,
+    pub(crate) hash: String,
+    pub(crate) algorithm: Algorithm,
+    pub(crate) size: u64,
+    pub(crate) created: i64,
+    /// Who uploaded the file, if known
+    pub(crate) owner: Option<String>,
+    /// Who deleted the file, if known
+    pub(crate) deleted_by: Option<String>,
+    pub(crate) deleted: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) encoding: Option<Encoding>,
+    #[serde(skip)]
+    pub(crate) id: i64,
+    #[serde(skip)]
+    pub(crate) path: String,
+}
+
+impl Trashed {
+    fn from_row(row: &Row) -> rusqlite::Result<Trashed> {
+        let id: i64 = row.get(0)?;
+        let algorithm: String = row.get(3)?;
+        let size: i64 = row.get(4)?;
+        let encoding: Option<String> = row.get(9)?;
+
+        Ok(Trashed {
+            filename: row.get(1)?,
+            hash: row.get(2)?,
+            algorithm: Algorithm::parse(&algorithm).unwrap_or(Algorithm::Md5),
+            size: size as u64,
+            created: row.get(5)?,
+            owner: row.get(6)?,
+            deleted_by: row.get(7)?,
+            deleted: row.get(8)?,
+            encoding: encoding.as_deref().and_then(Encoding::parse),
+            id,
+            path: Trash::path(id).to_string_lossy().to_string(),
+        })
+    }
+}
+
+const COLUMNS: &str =
+    "id, filename, hash, algorithm, size, created, owner, deleted_by, deleted, encoding";
+
+/// Deleted files, kept in `./trash` and tracked in the `trash` table.
+///
+/// DELETE moves a file here instead of removing it. UNDELETE moves it back, PURGE removes it
+/// for good, and files older than `trash_retention_secs` are purged automatically.
+pub struct Trash;
+
+impl Trash {
+    /// Create the trash table and directory, should be called once at startup
+    /// # Examples
+    /// ```
+    /// Trash::initialize(log.clone());
+    /// ```
+    pub fn initialize(log: Logger) {
+        info!(log, "Initializing trash");
+        let conn = Connection::open(DATABASE).unwrap();
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS trash (
+                  id              INTEGER PRIMARY KEY,
+                  filename        TEXT NOT NULL,
+                  hash            TEXT NOT NULL,
+                  algorithm       TEXT NOT NULL,
+                  size            INTEGER NOT NULL,
+                  created         INTEGER NOT NULL,
+                  owner           TEXT,
+                  deleted_by      TEXT,
+                  deleted         INTEGER NOT NULL
+                  )",
+            params![],
+        )
+        .unwrap();
+        add_column(&conn, "trash", "encoding", "TEXT");
+
+        if let Err(e) = fs::create_dir_all(env::current_dir().unwrap().join(TRASH_ROOT)) {
+            error!(log, "Error occurred: {:?}", e);
+        }
+    }
+
+    /// Whether DELETE moves files to the trash, `trash_retention_secs` 0 removes them directly
+    pub fn enabled() -> bool {
+        Config::get().trash_retention_secs > 0
+    }
+
+    fn path(id: i64) -> PathBuf {
+        env::current_dir()
+            .unwrap()
+            .join(TRASH_ROOT)
+            .join(id.to_string())
+    }
+
+    /// Move a file out of the file root into the trash
+    /// # Examples
+    /// ```
+    /// Trash::discard(&file, Some("alice"), owner, log.clone())?;
+    /// ```
+    pub fn discard(
+        file: &TFile,
+        deleted_by: Option<&str>,
+        owner: Option<String>,
+        log: Logger,
+    ) -> io::Result<()> {
+        let conn = Connection::open(DATABASE).map_err(io::Error::other)?;
+        conn.execute(
+            "INSERT INTO trash
+                  (filename, hash, algorithm, size, created, owner, deleted_by, deleted, encoding)
+                  VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                file.filename,
+                file.hash,
+                file.algorithm.name(),
+                file.size as i64,
+                file.created,
+                owner,
+                deleted_by,
+                Utc::now().timestamp(),
+                file.encoding.map(Encoding::name)
+            ],
+        )
+        .map_err(io::Error::other)?;
+        let id = conn.last_insert_rowid();
+
+        if let Err(e) = fs::rename(&file.path, Trash::path(id)) {
+            let _ = conn.execute("DELETE FROM trash WHERE id = ?1", params![id]);
+            return Err(e);
+        }
+
+        info!(log, "Moved {} to the trash", file.filename);
+        Ok(())
+    }
+
+    /// Deleted files whose name starts with `prefix`, most recently deleted first
+    pub fn list(prefix: &str) -> rusqlite::Result<Vec<Trashed>> {
+        let conn = Connection::open(DATABASE)?;
+        let mut statement = conn.prepare(&format!(
+            "SELECT {} FROM trash WHERE substr(filename, 1, length(?1)) = ?1
+              ORDER BY deleted DESC, id DESC",
+            COLUMNS
+        ))?;
+
+        let rows = statement.query_map(params![prefix], Trashed::from_row)?;
+        rows.collect()
+    }
+
+    /// The most recently deleted file with this name
+    pub fn latest(file_name: &str) -> Option<Trashed> {
+        let conn = Connection::open(DATABASE).ok()?;
+        conn.query_row(
+            &format!(
+                "SELECT {} FROM trash WHERE filename = ?1 ORDER BY deleted DESC, id DESC LIMIT 1",
+                COLUMNS
+            ),
+            params![file_name],
+            Trashed::from_row,
+        )
+        .optional()
+        .unwrap_or(None)
+    }
+
+    /// Move a file out of the trash to `path`
+    pub fn take(trashed: &Trashed, path: &Path) -> io::Result<()> {
+        fs::rename(&trashed.path, path)?;
+        let conn = Connection::open(DATABASE).map_err(io::Error::other)?;
+        conn.execute("DELETE FROM trash WHERE id = ?1", params![trashed.id])
+            .map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    /// Remove every deleted file with this name for good, returns how many there were
+    pub fn purge(file_name: &str, log: Logger) -> rusqlite::Result<usize> {
+        let conn = Connection::open(DATABASE)?;
+        let mut statement = conn.prepare("SELECT id FROM trash WHERE filename = ?1")?;
+        let ids: Vec<i64> = statement
+            .query_map(params![file_name], |row| row.get(0))?
+            .filter_map(|row| row.ok())
+            .collect();
+
+        for id in &ids {
+            Trash::remove(&conn, *id, log.clone())?;
+        }
+
+        info!(log, "Purged {} deleted copies of {}", ids.len(), file_name);
+        Ok(ids.len())
+    }
+
+    fn remove(conn: &Connection, id: i64, log: Logger) -> rusqlite::Result<()> {
+        let (algorithm, hash): (String, String) = conn.query_row(
+            "SELECT algorithm, hash FROM trash WHERE id = ?1",
+            params![id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+
+        let _ = fs::remove_file(Trash::path(id));
+        conn.execute("DELETE FROM trash WHERE id = ?1", params![id])?;
+
+        if let Some(algorithm) = Algorithm::parse(&algorithm) {
+            Blobs::release(algorithm, &hash, log);
+        }
+        Ok(())
+    }
+
+    /// Purge files deleted longer than `trash_retention_secs` ago
+    pub fn purge_expired(log: Logger) {
+        let oldest = Utc::now().timestamp() - Config::get().trash_retention_secs as i64;
+        let conn = Connection::open(DATABASE).unwrap();
+
+        let mut statement = conn
+            .prepare("SELECT id, filename FROM trash WHERE deleted < ?1")
+            .unwrap();
+        let expired: Vec<(i64, String)> = statement
+            .query_map(params![oldest], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .filter_map(|row| row.ok())
+            .collect();
+
+        for (id, filename) in expired {
+            info!(log, "Purging expired deleted file {}", filename);
+            if let Err(e) = Trash::remove(&conn, id, log.clone()) {
+                error!(log, "Failed to purge {}: {}", filename, e);
+            }
+        }
+    }
+
+    /// Purge expired files now and then on a thread of its own
+    pub fn spawn(log: Logger) {
+        let retention = Config::get().trash_retention_secs;
+        if retention == 0 {
+            return;
+        }
+
+        info!(log, "Keeping deleted files for {}s", retention);
+        thread::spawn(move || loop {
+            Trash::purge_expired(log.clone());
+            thread::sleep(Duration::from_secs(retention.min(PURGE_INTERVAL_SECS)));
+        });
+    }
+}
@@ -0,0 +1,252 @@
+use crate::blobs::Blobs;
+use crate::compression::Encoding;
+use crate::config::Config;
+use crate::file_manager::{add_column, TFile, DATABASE};
+use crate::hash::Algorithm;
+use crate::quota::Quota;
+
+use chrono::Utc;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::Serialize;
+use slog::Logger;
+use slog::*;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::{env, fs, thread};
+
+// earlier versions of files, named by their id in the version table
+const VERSION_ROOT: &str = "./versions";
+
+// longest wait between two sweeps for expired versions
+const PRUNE_INTERVAL_SECS: u64 = 3600;
+
+/// An earlier version of a file, kept when a PUT or RESTORE replaced it
+#[derive(Debug, Clone, Serialize)]
+pub struct Version {
+    pub(crate) filename: String,
+    /// Counts up from 1 for each file
+    pub(crate) version: i64,
+    pub(crate) hash: String,
+    pub(crate) algorithm: Algorithm,
+    pub(crate) size: u64,
+    /// When this content was uploaded
+    pub(crate) created: i64,
+    /// Who uploaded this content, if known
+    pub(crate) uploader: Option<String>,
+    /// When this content was replaced
+    pub(crate) archived: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) encoding: Option<Encoding>,
+    #[serde(skip)]
+    pub(crate) path: String,
+}
+
+impl Version {
+    fn from_row(row: &Row) -> rusqlite::Result<Version> {
+        let id: i64 = row.get(0)?;
+        let algorithm: String = row.get(4)?;
+        let size: i64 = row.get(5)?;
+        let encoding: Option<String> = row.get(9)?;
+
+        Ok(Version {
+            filename: row.get(1)?,
+            version: row.get(2)?,
+            hash: row.get(3)?,
+            algorithm: Algorithm::parse(&algorithm).unwrap_or(Algorithm::Md5),
+            size: size as u64,
+            created: row.get(6)?,
+            uploader: row.get(7)?,
+            archived: row.get(8)?,
+            encoding: encoding.as_deref().and_then(Encoding::parse),
+            path: Versions::path(id).to_string_lossy().to_string(),
+        })
+    }
+}
+
+const COLUMNS: &str =
+    "id, filename, version, hash, algorithm, size, created, uploader, archived, encoding";
+
+/// Earlier versions of files, kept in `./versions` and tracked in the `version` table.
+///
+/// Before a file is replaced, its content is hard linked into the versions area. At most
+/// `keep_versions` versions are kept per file, and none older than `version_retention_secs`.
+/// Both are applied whenever a file gets a new version and by a periodic sweep over all files.
+pub struct Versions;
+
+impl Versions {
+    /// Create the version table and directory, should be called once at startup
+    /// # Examples
+    /// ```
+    /// Versions::initialize(log.clone());
+    /// ```
+    pub fn initialize(log: Logger) {
+        info!(log, "Initializing file versions");
+        let conn = Connection::open(DATABASE).unwrap();
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS version (
+                  id              INTEGER PRIMARY KEY,
+                  filename        TEXT NOT NULL,
+                  version         INTEGER NOT NULL,
+                  hash            TEXT NOT NULL,
+                  algorithm       TEXT NOT NULL,
+                  size            INTEGER NOT NULL,
+                  created         INTEGER NOT NULL,
+                  uploader        TEXT,
+                  archived        INTEGER NOT NULL,
+                  UNIQUE (filename, version)
+                  )",
+            params![],
+        )
+        .unwrap();
+        add_column(&conn, "version", "encoding", "TEXT");
+
+        if let Err(e) = fs::create_dir_all(env::current_dir().unwrap().join(VERSION_ROOT)) {
+            error!(log, "Error occurred: {:?}", e);
+        }
+    }
+
+    fn path(id: i64) -> PathBuf {
+        env::current_dir()
+            .unwrap()
+            .join(VERSION_ROOT)
+            .join(id.to_string())
+    }
+
+    /// Keep the current content of a file as a version before it is replaced
+    /// # Examples
+    /// ```
+    /// Versions::archive(&current, log.clone())?;
+    /// ```
+    pub fn archive(file: &TFile, log: Logger) -> io::Result<()> {
+        let keep_versions = Config::get().keep_versions;
+        if keep_versions == 0 {
+            return Ok(());
+        }
+
+        let uploader = Quota::owner(&file.filename);
+        let conn = Connection::open(DATABASE).map_err(io::Error::other)?;
+        conn.execute(
+            "INSERT INTO version
+                  (filename, version, hash, algorithm, size, created, uploader, archived, encoding)
+                  SELECT ?1, COALESCE(MAX(version), 0) + 1, ?2, ?3, ?4, ?5, ?6, ?7, ?8
+                  FROM version WHERE filename = ?1",
+            params![
+                file.filename,
+                file.hash,
+                file.algorithm.name(),
+                file.size as i64,
+                file.created,
+                uploader,
+                Utc::now().timestamp(),
+                file.encoding.map(Encoding::name)
+            ],
+        )
+        .map_err(io::Error::other)?;
+        let id = conn.last_insert_rowid();
+
+        // the link keeps the content when the file itself is replaced
+        if let Err(e) = fs::hard_link(&file.path, Versions::path(id)) {
+            let _ = conn.execute("DELETE FROM version WHERE id = ?1", params![id]);
+            return Err(e);
+        }
+
+        info!(log, "Archived the previous version of {}", file.filename);
+        Versions::prune(&conn, &file.filename, log);
+        Ok(())
+    }
+
+    /// Drop the versions of a file beyond `keep_versions` or `version_retention_secs`
+    fn prune(conn: &Connection, file_name: &str, log: Logger) {
+        let config = Config::get();
+        let oldest = config
+            .version_retention_secs
+            .map_or(i64::MIN, |secs| Utc::now().timestamp() - secs as i64);
+
+        let mut statement = conn
+            .prepare(
+                "SELECT id, version, algorithm, hash FROM version WHERE filename = ?1
+                  AND (archived < ?2 OR id NOT IN (
+                      SELECT id FROM version WHERE filename = ?1
+                      ORDER BY version DESC LIMIT ?3))",
+            )
+            .unwrap();
+        let expired: Vec<(i64, i64, String, String)> = statement
+            .query_map(
+                params![file_name, oldest, config.keep_versions as i64],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap()
+            .filter_map(|row| row.ok())
+            .collect();
+
+        for (id, version, algorithm, hash) in expired {
+            info!(log, "Dropping version {} of {}", version, file_name);
+            let _ = fs::remove_file(Versions::path(id));
+            let _ = conn.execute("DELETE FROM version WHERE id = ?1", params![id]);
+            if let Some(algorithm) = Algorithm::parse(&algorithm) {
+                Blobs::release(algorithm, &hash, log.clone());
+            }
+        }
+    }
+
+    /// Drop the versions beyond `keep_versions` or `version_retention_secs` of every file,
+    /// including files that are never replaced again
+    pub fn prune_expired(log: Logger) {
+        let conn = Connection::open(DATABASE).unwrap();
+        let mut statement = conn
+            .prepare("SELECT DISTINCT filename FROM version")
+            .unwrap();
+        let file_names: Vec<String> = statement
+            .query_map(params![], |row| row.get(0))
+            .unwrap()
+            .filter_map(|row| row.ok())
+            .collect();
+
+        for file_name in file_names {
+            Versions::prune(&conn, &file_name, log.clone());
+        }
+    }
+
+    /// Prune expired versions now and then on a thread of its own
+    pub fn spawn(log: Logger) {
+        let interval = Config::get()
+            .version_retention_secs
+            .map_or(PRUNE_INTERVAL_SECS, |secs| {
+                secs.clamp(1, PRUNE_INTERVAL_SECS)
+            });
+
+        thread::spawn(move || loop {
+            Versions::prune_expired(log.clone());
+            thread::sleep(Duration::from_secs(interval));
+        });
+    }
+
+    /// Versions of a file, newest first
+    pub fn list(file_name: &str) -> rusqlite::Result<Vec<Version>> {
+        let conn = Connection::open(DATABASE)?;
+        let mut statement = conn.prepare(&format!(
+            "SELECT {} FROM version WHERE filename = ?1 ORDER BY version DESC",
+            COLUMNS
+        ))?;
+
+        let rows = statement.query_map(params![file_name], Version::from_row)?;
+        rows.collect()
+    }
+
+    /// A single version of a file
+    pub fn find(file_name: &str, version: i64) -> Option<Version> {
+        let conn = Connection::open(DATABASE).ok()?;
+        conn.query_row(
+            &format!(
+                "SELECT {} FROM version WHERE filename = ?1 AND version = ?2",
+                COLUMNS
+            ),
+            params![file_name, version],
+            Version::from_row,
+        )
+        .optional()
+        .unwrap_or(None)
+    }
+}
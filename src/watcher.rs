@@ -0,0 +1,104 @@
+use crate::file_manager::{FileManager, FILE_ROOT};
+
+use inotify::{EventMask, Inotify, WatchMask};
+use slog::Logger;
+use slog::*;
+use std::collections::BTreeSet;
+use std::io;
+use std::{env, fs, thread};
+
+// room for a few hundred events per read
+const EVENT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Keeps the index in sync with changes other processes make to the file root, using inotify.
+///
+/// A file is hashed again once it has been closed after writing or moved into the root, and
+/// removed from the index once it is deleted or moved out. If the kernel drops events, the
+/// whole root is compared with the index.
+pub struct Watcher;
+
+impl Watcher {
+    /// Start watching the file root on a thread of its own
+    /// # Examples
+    /// ```
+    /// Watcher::spawn(log.clone())?;
+    /// ```
+    pub fn spawn(log: Logger) -> io::Result<()> {
+        let root = env::current_dir()?.join(FILE_ROOT);
+        let mut inotify = Inotify::init()?;
+        inotify.add_watch(
+            &root,
+            WatchMask::CLOSE_WRITE
+                | WatchMask::MOVED_TO
+                | WatchMask::MOVED_FROM
+                | WatchMask::DELETE,
+        )?;
+
+        info!(log, "Watching {:?} for changes", root);
+        thread::spawn(move || Watcher::run(inotify, log));
+        Ok(())
+    }
+
+    fn run(mut inotify: Inotify, log: Logger) {
+        let mut buffer = vec![0_u8; EVENT_BUFFER_SIZE];
+
+        loop {
+            let events = match inotify.read_events_blocking(&mut buffer) {
+                Ok(events) => events,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!(log, "Stopped watching the file root: {}", e);
+                    return;
+                }
+            };
+
+            // a file written in several steps is only hashed once per batch
+            let mut changed = BTreeSet::new();
+            let mut overflow = false;
+
+            for event in events {
+                if event.mask.contains(EventMask::Q_OVERFLOW) {
+                    overflow = true;
+                } else if event.mask.contains(EventMask::ISDIR) {
+                    continue;
+                } else if let Some(name) = event.name.and_then(|name| name.to_str()) {
+                    changed.insert(name.to_string());
+                }
+            }
+
+            if overflow {
+                warn!(log, "Missed changes to the file root, comparing all files");
+                changed.extend(Watcher::all_names());
+            }
+
+            for file_name in changed {
+                if FileManager::is_valid_name(&file_name) {
+                    FileManager::sync_file(&file_name, log.clone());
+                }
+            }
+        }
+    }
+
+    /// Names in the file root and in the index
+    fn all_names() -> BTreeSet<String> {
+        let mut names: BTreeSet<String> = FileManager::get()
+            .lock()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .list()
+            .into_iter()
+            .map(|file| file.filename)
+            .collect();
+
+        if let Ok(entries) = fs::read_dir(env::current_dir().unwrap().join(FILE_ROOT)) {
+            names.extend(
+                entries
+                    .flatten()
+                    .filter_map(|entry| entry.file_name().into_string().ok()),
+            );
+        }
+
+        names
+    }
+}
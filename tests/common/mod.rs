@@ -0,0 +1,217 @@
+// shared by the integration tests, each of them uses a part of it
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const BINARY: &str = env!("CARGO_BIN_EXE_socket-server");
+
+/// A server running in a directory of its own, stopped and removed when dropped
+pub struct Server {
+    dir: PathBuf,
+    port: u16,
+    child: Child,
+}
+
+impl Server {
+    /// Start a server in a new directory after running the administrative commands in
+    /// `setup` there, `config` is added to its configuration
+    pub fn start(name: &str, config: &str, setup: &[&[&str]]) -> Server {
+        let dir = env::temp_dir().join(format!("socket-server-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        fs::write(
+            dir.join("server.toml"),
+            format!(
+                "listen = \"127.0.0.1:{}\"\nscrub_interval_secs = 0\nwatch_files = false\n{}",
+                port, config
+            ),
+        )
+        .unwrap();
+
+        for args in setup {
+            run(&dir, args);
+        }
+
+        let child = Server::spawn(&dir, port);
+        Server { dir, port, child }
+    }
+
+    fn spawn(dir: &Path, port: u16) -> Child {
+        let child = Command::new(BINARY)
+            .current_dir(dir)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        let started = Instant::now();
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "server didn't start"
+            );
+            thread::sleep(Duration::from_millis(50));
+        }
+        child
+    }
+
+    /// Path of a file in the server's directory
+    pub fn path(&self, relative: &str) -> PathBuf {
+        self.dir.join(relative)
+    }
+
+    /// Kill the server without letting it shut down cleanly and start it again
+    pub fn crash_and_restart(&mut self) {
+        self.child.kill().unwrap();
+        self.child.wait().unwrap();
+        self.child = Server::spawn(&self.dir, self.port);
+    }
+
+    /// Run an administrative command next to the running server and return what it printed
+    pub fn command(&self, args: &[&str]) -> String {
+        let output = Command::new(BINARY)
+            .args(args)
+            .current_dir(&self.dir)
+            .stderr(Stdio::null())
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?} failed", args);
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    pub fn connect(&self) -> Client {
+        let stream = TcpStream::connect(("127.0.0.1", self.port)).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        Client {
+            reader: BufReader::new(stream),
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+// run an administrative command, `adduser` reads the password from the last argument
+fn run(dir: &Path, args: &[&str]) {
+    let (args, password) = match args {
+        ["adduser", username, password] => (vec!["adduser", *username], Some(*password)),
+        _ => (args.to_vec(), None),
+    };
+
+    let mut child = Command::new(BINARY)
+        .args(&args)
+        .current_dir(dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    if let Some(password) = password {
+        writeln!(child.stdin.take().unwrap(), "{}", password).unwrap();
+    }
+    assert!(child.wait().unwrap().success(), "{:?} failed", args);
+}
+
+pub struct Response {
+    pub status: String,
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+pub struct Client {
+    reader: BufReader<TcpStream>,
+}
+
+impl Client {
+    pub fn send(&mut self, data: &[u8]) {
+        self.reader.get_mut().write_all(data).unwrap();
+    }
+
+    pub fn response(&mut self) -> Response {
+        let mut status = String::new();
+        self.reader.read_line(&mut status).unwrap();
+
+        let mut headers = HashMap::new();
+        loop {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            match line.trim().split_once(':') {
+                Some((name, value)) => {
+                    headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+                }
+                None => break,
+            }
+        }
+
+        let length = headers
+            .get("content-length")
+            .map_or(0, |length| length.parse().unwrap());
+        let mut body = vec![0; length];
+        self.reader.read_exact(&mut body).unwrap();
+
+        Response {
+            status: status.trim().to_string(),
+            headers,
+            body,
+        }
+    }
+
+    pub fn request(&mut self, line: &str) -> Response {
+        self.send(format!("{} AFTP/1.0\n", line).as_bytes());
+        self.response()
+    }
+
+    pub fn login(&mut self, username: &str, password: &str) {
+        let response = self.request(&format!("LOGIN {} {}", username, password));
+        assert_eq!(response.status, "AFTP/1.0 200 OK");
+    }
+
+    pub fn put(&mut self, name: &str, content: &[u8]) -> Response {
+        self.send(
+            format!(
+                "PUT {} AFTP/1.0\nContent-Length: {}\nHash: {:x}\n\n",
+                name,
+                content.len(),
+                md5::compute(content)
+            )
+            .as_bytes(),
+        );
+        self.send(content);
+        self.response()
+    }
+
+    // announce the content and wait whether the server wants the body
+    pub fn put_by_hash(&mut self, name: &str, content: &[u8]) -> Response {
+        self.send(
+            format!(
+                "PUT {} AFTP/1.0\nContent-Length: {}\nHash: {:x}\nExpect: 100-continue\n\n",
+                name,
+                content.len(),
+                md5::compute(content)
+            )
+            .as_bytes(),
+        );
+        self.response()
+    }
+}
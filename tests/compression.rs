@@ -0,0 +1,90 @@
+mod common;
+
+use common::Server;
+use std::fs;
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+#[test]
+fn compressed_files_survive_a_crash() {
+    let mut server = Server::start(
+        "compression",
+        "require_login = false\ncompression = \"zstd\"\n",
+        &[],
+    );
+    let content = b"a,b,c\n".repeat(1000);
+
+    let mut client = server.connect();
+    assert_eq!(client.put("log.csv", &content).status, "AFTP/1.0 200 OK");
+    assert_eq!(client.request("GET log.csv").body, content);
+
+    server.crash_and_restart();
+
+    let mut client = server.connect();
+    let response = client.request("GET log.csv");
+    assert_eq!(response.headers["file-size"], content.len().to_string());
+    assert_eq!(response.body, content);
+}
+
+fn touch(path: &Path) {
+    let file = OpenOptions::new().write(true).open(path).unwrap();
+    file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1_577_836_800))
+        .unwrap();
+}
+
+#[test]
+fn touched_compressed_files_keep_their_encoding() {
+    let mut server = Server::start(
+        "compression-touched",
+        "require_login = false\ncompression = \"gzip\"\n",
+        &[],
+    );
+    let content = b"a,b,c\n".repeat(1000);
+
+    let mut client = server.connect();
+    assert_eq!(client.put("a.log", &content).status, "AFTP/1.0 200 OK");
+
+    touch(&server.path("server_files/a.log"));
+    server.crash_and_restart();
+
+    let mut client = server.connect();
+    let stat = String::from_utf8(client.request("STAT a.log format=json").body).unwrap();
+    assert!(
+        stat.contains(&format!("\"hash\":\"{:X}\"", md5::compute(&content))),
+        "{}",
+        stat
+    );
+    assert!(
+        stat.contains(&format!("\"size\":{}", content.len())),
+        "{}",
+        stat
+    );
+    assert!(stat.contains("\"encoding\":\"gzip\""), "{}", stat);
+
+    let response = client.request("GET a.log");
+    assert_eq!(response.headers["file-size"], content.len().to_string());
+    assert_eq!(response.body, content);
+}
+
+#[test]
+fn compressed_files_replaced_by_plain_content_are_plain() {
+    let mut server = Server::start(
+        "compression-replaced",
+        "require_login = false\ncompression = \"zstd\"\n",
+        &[],
+    );
+
+    let mut client = server.connect();
+    let response = client.put("a.log", &b"a,b,c\n".repeat(1000));
+    assert_eq!(response.status, "AFTP/1.0 200 OK");
+
+    let replaced = b"replaced by hand\n";
+    fs::write(server.path("server_files/a.log"), replaced).unwrap();
+    server.crash_and_restart();
+
+    let mut client = server.connect();
+    let response = client.request("GET a.log");
+    assert_eq!(response.headers["file-size"], replaced.len().to_string());
+    assert_eq!(response.body, replaced);
+}
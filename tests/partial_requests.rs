@@ -0,0 +1,58 @@
+mod common;
+
+use common::Server;
+use std::thread;
+use std::time::Duration;
+
+// a single worker, so a client holding on to it would stall everyone else
+fn start(name: &str) -> Server {
+    Server::start(
+        name,
+        "require_login = false\nworker_threads = 1\nread_timeout_secs = 2\n",
+        &[],
+    )
+}
+
+#[test]
+fn partial_request_doesnt_hold_a_worker() {
+    let server = start("partial");
+
+    let mut slow = server.connect();
+    slow.send(b"LI");
+    thread::sleep(Duration::from_millis(200));
+
+    let mut other = server.connect();
+    assert_eq!(other.request("LIST").status, "AFTP/1.0 200 OK");
+    assert_eq!(other.put("a.txt", b"hello\n").status, "AFTP/1.0 200 OK");
+
+    slow.send(b"ST AFTP/1.0\n");
+    let response = slow.response();
+    assert_eq!(response.status, "AFTP/1.0 200 OK");
+    assert_eq!(response.headers["total-count"], "1");
+}
+
+#[test]
+fn put_is_served_once_its_headers_arrived() {
+    let server = start("headers");
+
+    let mut slow = server.connect();
+    slow.send(b"PUT a.txt AFTP/1.0\nContent-Length: 6\nHash: b1946ac92492d2347c6235b4d2611184\n");
+    thread::sleep(Duration::from_millis(200));
+
+    let mut other = server.connect();
+    assert_eq!(other.request("LIST").status, "AFTP/1.0 200 OK");
+
+    slow.send(b"\nhello\n");
+    assert_eq!(slow.response().status, "AFTP/1.0 200 OK");
+    assert_eq!(slow.request("GET a.txt").body, b"hello\n");
+}
+
+#[test]
+fn stalled_request_times_out() {
+    let server = start("stalled");
+
+    let mut slow = server.connect();
+    slow.send(b"LIST AFTP/1.0\nLI");
+    assert_eq!(slow.response().status, "AFTP/1.0 200 OK");
+    assert_eq!(slow.response().status, "AFTP/1.0 408 Request Timeout");
+}
@@ -0,0 +1,56 @@
+mod common;
+
+use common::Server;
+
+fn start_with_acl(name: &str) -> Server {
+    Server::start(
+        name,
+        "",
+        &[
+            &["adduser", "alice", "alice-password"],
+            &["adduser", "bob", "bob-password"],
+            &["grant", "alice", "alice-", "rw"],
+            &["grant", "bob", "bob-", "rw"],
+        ],
+    )
+}
+
+const SECRET: &[u8] = b"alice's secret\n";
+
+#[test]
+fn known_content_readable_by_the_client_skips_the_body() {
+    let server = start_with_acl("readable");
+
+    let mut alice = server.connect();
+    alice.login("alice", "alice-password");
+    assert_eq!(alice.put("alice-secret", SECRET).status, "AFTP/1.0 200 OK");
+
+    let response = alice.put_by_hash("alice-copy", SECRET);
+    assert_eq!(response.status, "AFTP/1.0 200 OK");
+    assert_eq!(response.headers["already-present"], "true");
+    assert_eq!(alice.request("GET alice-copy").body, SECRET);
+}
+
+#[test]
+fn known_content_the_client_cant_read_needs_the_body() {
+    let server = start_with_acl("unreadable");
+
+    let mut alice = server.connect();
+    alice.login("alice", "alice-password");
+    assert_eq!(alice.put("alice-secret", SECRET).status, "AFTP/1.0 200 OK");
+
+    let mut bob = server.connect();
+    bob.login("bob", "bob-password");
+    assert_eq!(
+        bob.request("GET alice-secret").status,
+        "AFTP/1.0 403 Forbidden"
+    );
+
+    // knowing the hash doesn't get bob a copy
+    let response = bob.put_by_hash("bob-copy", SECRET);
+    assert_eq!(response.status, "AFTP/1.0 100 Continue");
+
+    bob.send(SECRET);
+    assert_eq!(bob.response().status, "AFTP/1.0 200 OK");
+    assert_eq!(bob.request("GET bob-copy").body, SECRET);
+}
@@ -0,0 +1,42 @@
+mod common;
+
+use common::Server;
+
+fn start(name: &str) -> Server {
+    Server::start(
+        name,
+        "token_key_file = \"token.key\"\n",
+        &[
+            &["adduser", "alice", "alice-password"],
+            &["grant", "alice", "reports-", "rw"],
+            // creates the signing key before the server loads it
+            &["token", "alice", "r", "reports-", "60"],
+        ],
+    )
+}
+
+#[test]
+fn token_acts_as_its_user() {
+    let server = start("token-user");
+
+    let mut alice = server.connect();
+    alice.login("alice", "alice-password");
+    assert_eq!(alice.put("reports-q1", b"42\n").status, "AFTP/1.0 200 OK");
+
+    let token = server.command(&["token", "alice", "r", "reports-", "60"]);
+    let response = server
+        .connect()
+        .request(&format!("GET reports-q1 token={}", token));
+    assert_eq!(response.status, "AFTP/1.0 200 OK");
+    assert_eq!(response.body, b"42\n");
+}
+
+#[test]
+fn token_for_an_unknown_user_is_rejected() {
+    let server = start("token-unknown");
+
+    let token = server.command(&["token", "mallory", "r", "reports-", "60"]);
+    let response = server.connect().request(&format!("LIST token={}", token));
+    assert_eq!(response.status, "AFTP/1.0 401 Unauthorized");
+    assert_eq!(response.body, b"invalid token: unknown user: mallory\n");
+}